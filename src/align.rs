@@ -0,0 +1,386 @@
+use crate::diff::{items, Item, Value};
+use crate::model::UnpackedArchive;
+use crate::structured::is_zero;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+// Sections whose differing middles would need a larger table than this are
+// matched up position by position instead.
+const MAX_ALIGN_CELLS: usize = 1 << 24;
+
+/// Positions of a slot in two versions of a section, either of which may
+/// be missing.
+pub(crate) type Pair = (Option<usize>, Option<usize>);
+
+/// Identifies a section of an archive: a label and the words after it, up
+/// to the next label at a different address. Data before the first label
+/// has no label. `index` counts earlier sections with the same label.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) struct SectionKey {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub index: usize,
+}
+
+impl fmt::Display for SectionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.label {
+            Some(label) => write!(f, "{}", label)?,
+            None => write!(f, "start")?,
+        }
+        match self.index {
+            0 => Ok(()),
+            index => write!(f, "#{}", index),
+        }
+    }
+}
+
+// Pointers target a slot within a section rather than an address, so the
+// same pointer unpacked from different versions can be matched up.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Target {
+    Word {
+        section: SectionKey,
+        position: usize,
+        offset: usize,
+    },
+    End,
+}
+
+/// The contents of a word or label, independent of where it was unpacked.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Slot {
+    Label(String),
+    Pointer(Target),
+    Null,
+    String(String),
+    Raw(String),
+}
+
+/// The labels and words of one section, in order. The section's own label
+/// comes first.
+///
+/// `items` describes where each slot was unpacked from.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Section {
+    pub key: SectionKey,
+    pub slots: Vec<Slot>,
+    pub items: Vec<Item>,
+}
+
+impl Section {
+    pub fn location(&self, position: usize) -> String {
+        match self.items.get(position) {
+            Some(item) => item.location.clone(),
+            None => format!("{}[{}]", self.key, position),
+        }
+    }
+}
+
+pub(crate) fn sections(unpacked: &UnpackedArchive) -> anyhow::Result<Vec<Section>> {
+    // The first label at each address starts a new section.
+    let mut sections: Vec<Section> = Vec::new();
+    let mut counts: HashMap<Option<String>, usize> = HashMap::new();
+    let mut words: HashMap<usize, (usize, usize)> = HashMap::new();
+    let mut anchor: Option<usize> = None;
+    for item in items(unpacked) {
+        let label = match &item.value {
+            Value::Label(name) if anchor != Some(item.address) => Some(name.clone()),
+            _ => None,
+        };
+        if label.is_some() || sections.is_empty() {
+            if label.is_some() {
+                anchor = Some(item.address);
+            }
+            let count = counts.entry(label.clone()).or_insert(0);
+            sections.push(Section {
+                key: SectionKey {
+                    label,
+                    index: *count,
+                },
+                slots: Vec::new(),
+                items: Vec::new(),
+            });
+            *count += 1;
+        }
+        let current = sections.len() - 1;
+        let section = &mut sections[current];
+        if !matches!(item.value, Value::Label(_)) {
+            words.insert(item.address, (current, section.items.len()));
+        }
+        section.items.push(item);
+    }
+
+    let size = unpacked.size();
+    let keys: Vec<SectionKey> = sections.iter().map(|section| section.key.clone()).collect();
+    for section in &mut sections {
+        for item in &section.items {
+            let slot = match (&item.value, item.target) {
+                (_, Some(dest)) if dest >= size => Slot::Pointer(Target::End),
+                (_, Some(dest)) => {
+                    let (index, position) = words[&(dest - dest % 4)];
+                    Slot::Pointer(Target::Word {
+                        section: keys[index].clone(),
+                        position,
+                        offset: dest % 4,
+                    })
+                }
+                (Value::Label(name), None) => Slot::Label(name.clone()),
+                (Value::String(text), None) => Slot::String(text.clone()),
+                (Value::Raw(data), None) => Slot::Raw(data.clone()),
+                (Value::Null, None) => Slot::Null,
+                (Value::Pointer(pointer_id), None) => {
+                    return Err(anyhow::anyhow!("Unresolved pointer {}", pointer_id))
+                }
+            };
+            section.slots.push(slot);
+        }
+    }
+    Ok(sections)
+}
+
+// Pointers into the same section are candidates for matching even if the
+// word they target moved. Whether they really agree is decided once the
+// target section has been aligned.
+fn similar(a: &Slot, b: &Slot) -> bool {
+    match (a, b) {
+        (
+            Slot::Pointer(Target::Word {
+                section: a_section,
+                offset: a_offset,
+                ..
+            }),
+            Slot::Pointer(Target::Word {
+                section: b_section,
+                offset: b_offset,
+                ..
+            }),
+        ) => a_section == b_section && a_offset == b_offset,
+        (a, b) => a == b,
+    }
+}
+
+// Between matches, removed and added slots are paired up in order, so a
+// replaced word reads as a change rather than a removal and an addition.
+fn flush(pairs: &mut Vec<Pair>, old: &[usize], new: &[usize]) {
+    for index in 0..old.len().max(new.len()) {
+        pairs.push((old.get(index).copied(), new.get(index).copied()));
+    }
+}
+
+/// Matches up two sequences of slots, keeping as many in order as possible.
+///
+/// Returns pairs of positions in `old` and `new`, in order. A slot with no
+/// counterpart is paired with `None`.
+pub(crate) fn align(old: &[Slot], new: &[Slot]) -> Vec<Pair> {
+    let prefix = old
+        .iter()
+        .zip(new)
+        .take_while(|(a, b)| similar(a, b))
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| similar(a, b))
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    let mut pairs: Vec<Pair> = (0..prefix)
+        .map(|index| (Some(index), Some(index)))
+        .collect();
+    let mut removed: Vec<usize> = Vec::new();
+    let mut added: Vec<usize> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let width = b.len() + 1;
+    if (a.len() + 1).saturating_mul(width) <= MAX_ALIGN_CELLS {
+        // lengths[i * width + j] is the longest common subsequence of
+        // a[i..] and b[j..].
+        let mut lengths = vec![0u32; (a.len() + 1) * width];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lengths[i * width + j] = if similar(&a[i], &b[j]) {
+                    lengths[(i + 1) * width + j + 1] + 1
+                } else {
+                    lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+                };
+            }
+        }
+        while i < a.len() && j < b.len() {
+            if similar(&a[i], &b[j]) {
+                flush(&mut pairs, &removed, &added);
+                removed.clear();
+                added.clear();
+                pairs.push((Some(prefix + i), Some(prefix + j)));
+                i += 1;
+                j += 1;
+            } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+                removed.push(prefix + i);
+                i += 1;
+            } else {
+                added.push(prefix + j);
+                j += 1;
+            }
+        }
+    }
+    removed.extend((i..a.len()).map(|i| prefix + i));
+    added.extend((j..b.len()).map(|j| prefix + j));
+    flush(&mut pairs, &removed, &added);
+    let old_start = old.len() - suffix;
+    let new_start = new.len() - suffix;
+    pairs.extend((0..suffix).map(|index| (Some(old_start + index), Some(new_start + index))));
+    pairs
+}
+
+/// The alignment of every section two versions of an archive share.
+pub(crate) struct Alignment<'a> {
+    pairs: HashMap<&'a SectionKey, Vec<Pair>>,
+    forward: HashMap<&'a SectionKey, Vec<Option<usize>>>,
+    backward: HashMap<&'a SectionKey, Vec<Option<usize>>>,
+}
+
+impl<'a> Alignment<'a> {
+    pub fn new(old: &'a [Section], new: &'a [Section]) -> Self {
+        let new: HashMap<&SectionKey, &Section> =
+            new.iter().map(|section| (&section.key, section)).collect();
+        let mut alignment = Alignment {
+            pairs: HashMap::new(),
+            forward: HashMap::new(),
+            backward: HashMap::new(),
+        };
+        for section in old {
+            if let Some(other) = new.get(&section.key) {
+                let pairs = align(&section.slots, &other.slots);
+                let mut forward = vec![None; section.slots.len()];
+                let mut backward = vec![None; other.slots.len()];
+                for pair in &pairs {
+                    if let (Some(i), Some(j)) = *pair {
+                        forward[i] = Some(j);
+                        backward[j] = Some(i);
+                    }
+                }
+                alignment.pairs.insert(&section.key, pairs);
+                alignment.forward.insert(&section.key, forward);
+                alignment.backward.insert(&section.key, backward);
+            }
+        }
+        alignment
+    }
+
+    /// The aligned positions of a section both versions have.
+    pub fn pairs(&self, key: &SectionKey) -> &[Pair] {
+        self.pairs.get(key).map_or(&[], Vec::as_slice)
+    }
+
+    /// The position in the new version of a slot in the old version.
+    pub fn forward(&self, key: &SectionKey, position: usize) -> Option<usize> {
+        *self.forward.get(key)?.get(position)?
+    }
+
+    /// The position in the old version of a slot in the new version.
+    pub fn backward(&self, key: &SectionKey, position: usize) -> Option<usize> {
+        *self.backward.get(key)?.get(position)?
+    }
+
+    /// Where a pointer in the new version would point in the old version.
+    pub fn target_backward(&self, target: &Target) -> Option<Target> {
+        match target {
+            Target::Word {
+                section,
+                position,
+                offset,
+            } => Some(Target::Word {
+                section: section.clone(),
+                position: self.backward(section, *position)?,
+                offset: *offset,
+            }),
+            Target::End => Some(Target::End),
+        }
+    }
+
+    /// Whether a slot in the old version and one in the new version agree,
+    /// with pointers compared by the word they target.
+    pub fn same(&self, old: &Slot, new: &Slot) -> bool {
+        match (old, new) {
+            (Slot::Pointer(old), Slot::Pointer(new)) => {
+                self.target_backward(new).as_ref() == Some(old)
+            }
+            (old, new) => old == new,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(values: &[u32]) -> Vec<Slot> {
+        values
+            .iter()
+            .map(|value| Slot::Raw(format!("0x{:08X}", value)))
+            .collect()
+    }
+
+    #[test]
+    fn insertion_is_a_single_addition() {
+        let pairs = align(&raw(&[1, 2, 3]), &raw(&[7, 1, 2, 3]));
+        assert_eq!(
+            pairs,
+            vec![
+                (None, Some(0)),
+                (Some(0), Some(1)),
+                (Some(1), Some(2)),
+                (Some(2), Some(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn replacement_is_paired() {
+        let pairs = align(&raw(&[1, 2, 3, 4]), &raw(&[1, 5, 6, 4]));
+        assert_eq!(
+            pairs,
+            vec![
+                (Some(0), Some(0)),
+                (Some(1), Some(1)),
+                (Some(2), Some(2)),
+                (Some(3), Some(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn removal_and_insertion_in_the_middle() {
+        let pairs = align(&raw(&[1, 2, 3, 4, 5]), &raw(&[1, 3, 4, 8, 5]));
+        assert_eq!(
+            pairs,
+            vec![
+                (Some(0), Some(0)),
+                (Some(1), None),
+                (Some(2), Some(1)),
+                (Some(3), Some(2)),
+                (None, Some(3)),
+                (Some(4), Some(4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn pointers_into_a_section_are_matched_by_offset() {
+        let pointer = |position| {
+            Slot::Pointer(Target::Word {
+                section: SectionKey {
+                    label: Some("A".to_owned()),
+                    index: 0,
+                },
+                position,
+                offset: 0,
+            })
+        };
+        let pairs = align(&[pointer(1), Slot::Null], &[pointer(2), Slot::Null]);
+        assert_eq!(pairs, vec![(Some(0), Some(0)), (Some(1), Some(1))]);
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::Context;
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+
+pub struct Summary {
+    pub succeeded: Vec<(PathBuf, Vec<String>)>,
+    pub failed: Vec<(PathBuf, anyhow::Error)>,
+    pub skipped: Vec<PathBuf>,
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory '{}'.", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Recursively processes every file under `input_dir`, mirroring the
+/// directory structure into `output_dir`.
+///
+/// `output_name` maps a file name to the name of its output, or `None` to
+/// skip the file. `process` is given the input and output paths and returns
+/// messages to report for the file. Files are processed on up to `jobs`
+/// threads, but the summary is always in path order.
+pub fn run<N, P>(
+    input_dir: &Path,
+    output_dir: &Path,
+    jobs: Option<usize>,
+    output_name: N,
+    process: P,
+) -> anyhow::Result<Summary>
+where
+    N: Fn(&str) -> Option<String>,
+    P: Fn(&Path, &Path) -> anyhow::Result<Vec<String>> + Sync,
+{
+    let mut files = Vec::new();
+    collect_files(input_dir, &mut files)?;
+    files.sort();
+
+    let mut summary = Summary {
+        succeeded: Vec::new(),
+        failed: Vec::new(),
+        skipped: Vec::new(),
+    };
+    let mut tasks: Vec<(PathBuf, PathBuf, PathBuf)> = Vec::new();
+    for input in files {
+        let relative = input.strip_prefix(input_dir)?.to_owned();
+        match relative
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(&output_name)
+        {
+            Some(name) => {
+                let output = output_dir.join(&relative).with_file_name(name);
+                tasks.push((relative, input, output));
+            }
+            None => summary.skipped.push(relative),
+        }
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs.unwrap_or(0))
+        .build()
+        .context("Failed to start worker threads.")?;
+    let results: Vec<anyhow::Result<Vec<String>>> = pool.install(|| {
+        tasks
+            .par_iter()
+            .map(|(_, input, output)| {
+                output
+                    .parent()
+                    .map_or(Ok(()), std::fs::create_dir_all)
+                    .context("Failed to create output directory.")?;
+                process(input, output)
+            })
+            .collect()
+    });
+    for ((relative, _, _), result) in tasks.into_iter().zip(results) {
+        match result {
+            Ok(messages) => summary.succeeded.push((relative, messages)),
+            Err(err) => summary.failed.push((relative, err)),
+        }
+    }
+    Ok(summary)
+}
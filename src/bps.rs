@@ -0,0 +1,319 @@
+use anyhow::Context;
+use std::collections::HashMap;
+
+const MAGIC: &[u8] = b"BPS1";
+const FOOTER_SIZE: usize = 12;
+
+const SOURCE_READ: usize = 0;
+const TARGET_READ: usize = 1;
+const SOURCE_COPY: usize = 2;
+const TARGET_COPY: usize = 3;
+
+// Shorter matches cost more to encode than the bytes they replace.
+const MIN_MATCH: usize = 4;
+const MAX_CANDIDATES: usize = 32;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn write_number(patch: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let low = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            patch.push(0x80 | low);
+            break;
+        }
+        patch.push(low);
+        value -= 1;
+    }
+}
+
+fn write_action(patch: &mut Vec<u8>, command: usize, length: usize) {
+    write_number(patch, ((length - 1) << 2) | command);
+}
+
+fn match_length(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+pub fn is_bps(patch: &[u8]) -> bool {
+    patch.starts_with(MAGIC)
+}
+
+/// Creates a BPS patch that turns `source` into `target`.
+pub fn create_bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut patch = MAGIC.to_vec();
+    write_number(&mut patch, source.len());
+    write_number(&mut patch, target.len());
+    write_number(&mut patch, 0);
+
+    let mut index: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for start in 0..source.len().saturating_sub(MIN_MATCH - 1) {
+        let candidates = index.entry(&source[start..start + MIN_MATCH]).or_default();
+        if candidates.len() < MAX_CANDIDATES {
+            candidates.push(start);
+        }
+    }
+
+    let mut pending: Option<usize> = None;
+    let flush = |patch: &mut Vec<u8>, pending: &mut Option<usize>, end: usize| {
+        if let Some(start) = pending.take() {
+            write_action(patch, TARGET_READ, end - start);
+            patch.extend_from_slice(&target[start..end]);
+        }
+    };
+    let mut source_relative = 0;
+    let mut position = 0;
+    while position < target.len() {
+        let rest = &target[position..];
+        let read_length = match_length(source.get(position..).unwrap_or_default(), rest);
+        let (copy_start, copy_length) = rest
+            .get(..MIN_MATCH)
+            .and_then(|key| index.get(key))
+            .into_iter()
+            .flatten()
+            .map(|start| (*start, match_length(&source[*start..], rest)))
+            .max_by_key(|(_, length)| *length)
+            .unwrap_or((0, 0));
+
+        if read_length >= MIN_MATCH && read_length >= copy_length {
+            flush(&mut patch, &mut pending, position);
+            write_action(&mut patch, SOURCE_READ, read_length);
+            position += read_length;
+        } else if copy_length >= MIN_MATCH {
+            flush(&mut patch, &mut pending, position);
+            write_action(&mut patch, SOURCE_COPY, copy_length);
+            let (magnitude, negative) = if copy_start >= source_relative {
+                (copy_start - source_relative, 0)
+            } else {
+                (source_relative - copy_start, 1)
+            };
+            write_number(&mut patch, (magnitude << 1) | negative);
+            source_relative = copy_start + copy_length;
+            position += copy_length;
+        } else {
+            pending.get_or_insert(position);
+            position += 1;
+        }
+    }
+    flush(&mut patch, &mut pending, target.len());
+
+    patch.extend_from_slice(&crc32(source).to_le_bytes());
+    patch.extend_from_slice(&crc32(target).to_le_bytes());
+    let checksum = crc32(&patch);
+    patch.extend_from_slice(&checksum.to_le_bytes());
+    patch
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + length)
+            .context("BPS patch ends unexpectedly")?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn number(&mut self) -> anyhow::Result<usize> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.bytes(1)?[0] as usize;
+            value = (byte & 0x7F)
+                .checked_mul(shift)
+                .and_then(|part| value.checked_add(part))
+                .context("BPS patch contains an oversized number")?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift
+                .checked_shl(7)
+                .context("BPS patch contains an oversized number")?;
+            value = value
+                .checked_add(shift)
+                .context("BPS patch contains an oversized number")?;
+        }
+    }
+
+    fn relative(&mut self, base: usize) -> anyhow::Result<usize> {
+        let offset = self.number()?;
+        let position = if offset & 1 == 0 {
+            base.checked_add(offset >> 1)
+        } else {
+            base.checked_sub(offset >> 1)
+        };
+        position.context("BPS patch copies from outside the file")
+    }
+}
+
+/// Applies a BPS patch to `source`.
+///
+/// The checksums stored in the patch are checked against the patch itself,
+/// the source and the result.
+pub fn apply_bps(source: &[u8], patch: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if patch.len() < MAGIC.len() + FOOTER_SIZE || !patch.starts_with(MAGIC) {
+        return Err(anyhow::anyhow!("Not a BPS patch"));
+    }
+    let (body, footer) = patch.split_at(patch.len() - FOOTER_SIZE);
+    let checksum = |offset: usize| {
+        u32::from_le_bytes([
+            footer[offset],
+            footer[offset + 1],
+            footer[offset + 2],
+            footer[offset + 3],
+        ])
+    };
+    if crc32(&patch[..patch.len() - 4]) != checksum(8) {
+        return Err(anyhow::anyhow!("BPS patch is corrupt (checksum mismatch)"));
+    }
+    if crc32(source) != checksum(0) {
+        return Err(anyhow::anyhow!(
+            "Source does not match the patch: CRC32 is {:08X}, expected {:08X}",
+            crc32(source),
+            checksum(0)
+        ));
+    }
+
+    let mut reader = Reader {
+        data: body,
+        position: MAGIC.len(),
+    };
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata_size = reader.number()?;
+    reader.bytes(metadata_size)?;
+    if source_size != source.len() {
+        return Err(anyhow::anyhow!(
+            "Source does not match the patch: size is {} bytes, expected {}",
+            source.len(),
+            source_size
+        ));
+    }
+
+    let mut target: Vec<u8> = Vec::new();
+    let mut source_relative = 0;
+    let mut target_relative = 0;
+    while reader.position < body.len() {
+        let action = reader.number()?;
+        let length = (action >> 2) + 1;
+        if target.len() + length > target_size {
+            return Err(anyhow::anyhow!(
+                "BPS patch writes past the end of the target"
+            ));
+        }
+        match action & 3 {
+            SOURCE_READ => {
+                let start = target.len();
+                let bytes = source
+                    .get(start..start + length)
+                    .context("BPS patch reads past the end of the source")?;
+                target.extend_from_slice(bytes);
+            }
+            TARGET_READ => target.extend_from_slice(reader.bytes(length)?),
+            SOURCE_COPY => {
+                source_relative = reader.relative(source_relative)?;
+                let bytes = source
+                    .get(source_relative..source_relative + length)
+                    .context("BPS patch copies from outside the source")?;
+                target.extend_from_slice(bytes);
+                source_relative += length;
+            }
+            TARGET_COPY => {
+                // Target copies may overlap the bytes they produce.
+                target_relative = reader.relative(target_relative)?;
+                for _ in 0..length {
+                    let byte = *target
+                        .get(target_relative)
+                        .context("BPS patch copies from outside the target")?;
+                    target.push(byte);
+                    target_relative += 1;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    if target.len() != target_size || crc32(&target) != checksum(4) {
+        return Err(anyhow::anyhow!(
+            "Patched file does not match the checksum stored in the patch"
+        ));
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_checksums(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let checksum = crc32(&patch);
+        patch.extend_from_slice(&checksum.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn crc32_known_answers() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn applies_a_hand_written_patch() {
+        // SourceRead 4 bytes, then TargetRead "XY".
+        let mut patch = MAGIC.to_vec();
+        patch.extend_from_slice(&[0x84, 0x86, 0x80, 0x8C, 0x85, b'X', b'Y']);
+        let patch = with_checksums(patch, b"abcd", b"abcdXY");
+        assert_eq!(apply_bps(b"abcd", &patch).unwrap(), b"abcdXY");
+    }
+
+    #[test]
+    fn round_trips() {
+        let source: Vec<u8> = (0..2000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut target = source.clone();
+        target.splice(100..100, b"inserted".iter().copied());
+        target.drain(900..1000);
+        target[1500] ^= 0xFF;
+        target.extend_from_slice(&source[..300]);
+        for (source, target) in [
+            (&source, &target),
+            (&target, &source),
+            (&source, &Vec::new()),
+        ]
+        .iter()
+        {
+            let patch = create_bps(source, target);
+            assert!(is_bps(&patch));
+            assert_eq!(&apply_bps(source, &patch).unwrap(), *target);
+        }
+    }
+
+    #[test]
+    fn rejects_the_wrong_source() {
+        let patch = create_bps(b"source data", b"target data");
+        assert!(apply_bps(b"other data!", &patch).is_err());
+        let mut corrupt = patch.clone();
+        corrupt[MAGIC.len()] ^= 1;
+        assert!(apply_bps(b"source data", &corrupt).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use crate::align::{sections, Alignment, Section, SectionKey};
+use crate::model::{Entry, FieldValue, UnpackedArchive, NULL_POINTER};
+use crate::unpacker::format_word;
+use mila::BinArchive;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// The contents of a word or label, with pointers resolved to the location
+/// they target.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", content = "value", rename_all = "lowercase")]
+pub enum Value {
+    Label(String),
+    Pointer(String),
+    Null,
+    String(String),
+    Raw(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Label(name) => write!(f, "label {}", name),
+            Value::Pointer(target) => write!(f, "pointer to {}", target),
+            Value::Null => write!(f, "null pointer"),
+            Value::String(text) => write!(f, "string {:?}", text),
+            Value::Raw(data) => write!(f, "raw {}", data),
+        }
+    }
+}
+
+/// A difference between two archives.
+///
+/// Locations are written relative to the nearest label before them, so
+/// inserting data in one table does not show up as a change to every
+/// address after it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum Change {
+    Added {
+        location: String,
+        value: Value,
+    },
+    Removed {
+        location: String,
+        value: Value,
+    },
+    Changed {
+        location: String,
+        old: Value,
+        new: Value,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added { location, value } => write!(f, "+ {}: {}", location, value),
+            Change::Removed { location, value } => write!(f, "- {}: {}", location, value),
+            Change::Changed { location, old, new } => {
+                write!(f, "~ {}: {} -> {}", location, old, new)
+            }
+        }
+    }
+}
+
+/// A label or word, where it was unpacked from and what it contains.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Item {
+    pub location: String,
+    pub value: Value,
+    pub address: usize,
+    pub target: Option<usize>,
+}
+
+pub(crate) fn relative_to(anchor: Option<&(usize, &str)>, addr: usize) -> String {
+    match anchor {
+        Some((start, name)) if *start == addr => (*name).to_owned(),
+        Some((start, name)) => format!("{}+0x{:X}", name, addr - start),
+        None => format!("0x{:X}", addr),
+    }
+}
+
+fn location(anchors: &[(usize, &str)], addr: usize) -> String {
+    relative_to(
+        anchors
+            .iter()
+            .take_while(|(start, _)| *start <= addr)
+            .last(),
+        addr,
+    )
+}
+
+pub(crate) fn items(unpacked: &UnpackedArchive) -> Vec<Item> {
+    // The first label at each address anchors the locations after it.
+    let mut anchors: Vec<(usize, &str)> = Vec::new();
+    let mut destinations: HashMap<&str, usize> = HashMap::new();
+    let mut addr = 0;
+    for entry in &unpacked.entries {
+        match entry {
+            Entry::Label(name) => {
+                if anchors.last().map(|(start, _)| *start) != Some(addr) {
+                    anchors.push((addr, name));
+                }
+            }
+            Entry::PointerDest(pointer_id, offset) => {
+                destinations.insert(pointer_id, addr + offset);
+            }
+            entry => addr += entry.size(),
+        }
+    }
+
+    let mut items = Vec::new();
+    let mut addr = 0;
+    for entry in &unpacked.entries {
+        let mut target = None;
+        let value = match entry {
+            Entry::Label(name) => {
+                // An anchoring label is located relative to the label before it.
+                let location = if anchors.contains(&(addr, name.as_str())) {
+                    relative_to(
+                        anchors.iter().take_while(|(start, _)| *start < addr).last(),
+                        addr,
+                    )
+                } else {
+                    location(&anchors, addr)
+                };
+                items.push(Item {
+                    location,
+                    value: Value::Label(name.clone()),
+                    address: addr,
+                    target: None,
+                });
+                continue;
+            }
+            Entry::PointerDest(_, _) => continue,
+            Entry::Word(data) | Entry::Typed(_, data) => Value::Raw(format_word(data)),
+            Entry::Field(_, FieldValue::Numbers(_, bytes)) => Value::Raw(format_word(bytes)),
+            Entry::Text(text) | Entry::Field(_, FieldValue::String(text)) => {
+                Value::String(text.clone())
+            }
+            Entry::NullPointer => Value::Null,
+            Entry::Field(_, FieldValue::Pointer(pointer_id)) if pointer_id == NULL_POINTER => {
+                Value::Null
+            }
+            Entry::PointerSource(pointer_id) | Entry::Field(_, FieldValue::Pointer(pointer_id)) => {
+                match destinations.get(pointer_id.as_str()) {
+                    Some(dest) => {
+                        target = Some(*dest);
+                        Value::Pointer(location(&anchors, *dest))
+                    }
+                    None => Value::Pointer(pointer_id.clone()),
+                }
+            }
+        };
+        items.push(Item {
+            location: location(&anchors, addr),
+            value,
+            address: addr,
+            target,
+        });
+        addr += entry.size();
+    }
+    items
+}
+
+fn added(section: &Section) -> impl Iterator<Item = Change> + '_ {
+    section.items.iter().map(|item| Change::Added {
+        location: item.location.clone(),
+        value: item.value.clone(),
+    })
+}
+
+fn removed(section: &Section) -> impl Iterator<Item = Change> + '_ {
+    section.items.iter().map(|item| Change::Removed {
+        location: item.location.clone(),
+        value: item.value.clone(),
+    })
+}
+
+/// The changes between two unpacked versions of an archive, with the key
+/// of the section each one belongs to.
+pub(crate) fn compare<'a>(old: &'a [Section], new: &'a [Section]) -> Vec<(&'a SectionKey, Change)> {
+    let alignment = Alignment::new(old, new);
+    let old_keys: HashSet<&SectionKey> = old.iter().map(|section| &section.key).collect();
+    let new_sections: HashMap<&SectionKey, &Section> =
+        new.iter().map(|section| (&section.key, section)).collect();
+
+    // Keep the old order, placing new sections after the section that
+    // precedes them in `new`.
+    let mut inserted: HashMap<Option<&SectionKey>, Vec<&Section>> = HashMap::new();
+    let mut previous: Option<&SectionKey> = None;
+    for section in new {
+        if old_keys.contains(&section.key) {
+            previous = Some(&section.key);
+        } else {
+            inserted.entry(previous).or_default().push(section);
+        }
+    }
+
+    let mut changes = Vec::new();
+    for section in inserted.remove(&None).unwrap_or_default() {
+        changes.extend(added(section).map(|change| (&section.key, change)));
+    }
+    for section in old {
+        let key = &section.key;
+        match new_sections.get(key) {
+            None => changes.extend(removed(section).map(|change| (key, change))),
+            Some(other) => {
+                for pair in alignment.pairs(key) {
+                    let change = match *pair {
+                        (Some(i), None) => Change::Removed {
+                            location: section.items[i].location.clone(),
+                            value: section.items[i].value.clone(),
+                        },
+                        (None, Some(j)) => Change::Added {
+                            location: other.items[j].location.clone(),
+                            value: other.items[j].value.clone(),
+                        },
+                        (Some(i), Some(j))
+                            if !alignment.same(&section.slots[i], &other.slots[j]) =>
+                        {
+                            Change::Changed {
+                                location: section.items[i].location.clone(),
+                                old: section.items[i].value.clone(),
+                                new: other.items[j].value.clone(),
+                            }
+                        }
+                        _ => continue,
+                    };
+                    changes.push((key, change));
+                }
+            }
+        }
+        for section in inserted.remove(&Some(key)).unwrap_or_default() {
+            changes.extend(added(section).map(|change| (&section.key, change)));
+        }
+    }
+    changes
+}
+
+/// Compares two archives structurally.
+///
+/// The words after each label are aligned as a sequence, so inserting or
+/// removing a word shows up as a single change rather than shifting every
+/// word after it. Pointers are compared by the word they target, so
+/// renumbered pointer ids and shifted addresses are not reported. Changes
+/// are listed in the order of `old`, with additions placed where they occur
+/// in `new`.
+pub fn diff(old: &BinArchive, new: &BinArchive) -> anyhow::Result<Vec<Change>> {
+    let old = sections(&UnpackedArchive::from_archive(old)?)?;
+    let new = sections(&UnpackedArchive::from_archive(new)?)?;
+    Ok(compare(&old, &new)
+        .into_iter()
+        .map(|(_, change)| change)
+        .collect())
+}
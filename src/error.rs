@@ -0,0 +1,122 @@
+use crate::header::FORMAT_VERSION;
+use std::ops::Range;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum PackErrorKind {
+    #[error("bad hex string")]
+    BadHex,
+
+    #[error("hex string must be exactly 4 bytes")]
+    WrongHexLength,
+
+    #[error("unresolved pointer '{0}'")]
+    UnresolvedPointer(String),
+
+    #[error("duplicate destination '{0}'")]
+    DuplicateDest(String),
+
+    #[error("malformed {0} directive")]
+    MalformedDirective(String),
+
+    #[error("offset +{1} of destination '{0}' is outside the entry it marks")]
+    DestOutOfRange(String, usize),
+
+    #[error("'{0}' is reserved for null pointers")]
+    ReservedName(String),
+
+    #[error("unresolved merge conflict")]
+    ConflictMarker,
+
+    #[error("unknown field type '{0}'")]
+    UnknownType(String),
+
+    #[error("invalid {0} value")]
+    BadValue(String),
+
+    #[error("{0} directive must encode exactly 4 bytes")]
+    WrongWordSize(String),
+
+    #[error("{0} directive must come before any data")]
+    MisplacedDirective(String),
+
+    #[error("duplicate {0} directive")]
+    DuplicateDirective(String),
+
+    #[error(
+        "format version {0} is not supported (expected at most {})",
+        FORMAT_VERSION
+    )]
+    UnsupportedVersion(u32),
+
+    #[error("invalid escape sequence")]
+    BadEscape,
+
+    #[error("unterminated string")]
+    UnterminatedString,
+
+    #[error("file is {0}-endian, but {1}-endian was requested")]
+    EndianMismatch(String, String),
+}
+
+/// An error in a text file passed to `pack`.
+///
+/// `line` is 1-based. `span` is the byte range within `text` that the error
+/// points at.
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("{kind} at line {line}")]
+pub struct PackError {
+    pub line: usize,
+    pub text: String,
+    pub span: Range<usize>,
+    pub kind: PackErrorKind,
+}
+
+/// Every error found in a text file, up to a limit.
+///
+/// `total` counts all errors, including those past the limit that were not
+/// kept in `errors`.
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("{total} error(s) in input")]
+pub struct PackErrors {
+    pub errors: Vec<PackError>,
+    pub total: usize,
+}
+
+impl PackErrors {
+    pub(crate) fn new() -> Self {
+        PackErrors {
+            errors: Vec::new(),
+            total: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, error: PackError, limit: usize) {
+        self.total += 1;
+        if self.errors.len() < limit {
+            self.errors.push(error);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum PackWarningKind {
+    #[error("destination '{0}' is never referenced")]
+    UnusedDest(String),
+
+    #[error("read as a string, since only files with a version line have comments")]
+    CommentWithoutVersion,
+
+    #[error("read as a string, since only files with a version line have {0} lines")]
+    DirectiveWithoutVersion(String),
+}
+
+/// A problem in a text file that does not stop it from packing.
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("{kind} at line {line}")]
+pub struct PackWarning {
+    pub line: usize,
+    pub text: String,
+    pub span: Range<usize>,
+    pub kind: PackWarningKind,
+}
@@ -0,0 +1,67 @@
+use crate::schema::Endian;
+
+/// Version of the text format written by `unpack`.
+///
+/// `pack` rejects files that declare a newer version, since they may use
+/// syntax this version does not understand.
+pub const FORMAT_VERSION: u32 = 1;
+
+pub(crate) const VERSION_PREFIX: &str = "#! asset-pack v";
+
+/// How a bin file is compressed on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Lz13,
+}
+
+impl Compression {
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Lz13 => "lz13",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Compression::None),
+            "lz13" => Some(Compression::Lz13),
+            _ => None,
+        }
+    }
+}
+
+/// Metadata written at the top of an unpacked text file.
+///
+/// `compression`, `source` and `sha1` describe the file the text was
+/// unpacked from, and are omitted when unknown. `sha1` is the digest of the
+/// archive data after any decompression, so a repacked file can be checked
+/// against it even though LZ13 output differs between compressors.
+///
+/// `endian` is always written; a file with a version line but no `ENDIAN`
+/// is little-endian.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Header {
+    pub compression: Option<Compression>,
+    pub source: Option<String>,
+    pub sha1: Option<String>,
+    pub endian: Endian,
+}
+
+impl Header {
+    pub(crate) fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!("{}{}", VERSION_PREFIX, FORMAT_VERSION)];
+        if let Some(compression) = self.compression {
+            lines.push(format!("COMPRESSION: {}", compression.name()));
+        }
+        if let Some(source) = &self.source {
+            lines.push(format!("SOURCE: {}", source));
+        }
+        if let Some(sha1) = &self.sha1 {
+            lines.push(format!("SHA1: {}", sha1));
+        }
+        lines.push(format!("ENDIAN: {}", self.endian.name()));
+        lines
+    }
+}
@@ -0,0 +1,69 @@
+use crate::model::zero_is_null;
+use mila::BinArchive;
+use serde::Serialize;
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LabelInfo {
+    pub address: usize,
+    pub name: String,
+}
+
+/// Statistics about the contents of an archive.
+///
+/// `pointers` counts every pointer table entry, including the
+/// `null_pointers`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ArchiveInfo {
+    pub size: usize,
+    pub pointers: usize,
+    pub null_pointers: usize,
+    pub destinations: usize,
+    pub strings: usize,
+    pub labels: Vec<LabelInfo>,
+}
+
+pub fn archive_info(archive: &BinArchive) -> anyhow::Result<ArchiveInfo> {
+    let mut pointers = 0;
+    let mut null_pointers = 0;
+    let mut destinations: HashSet<usize> = HashSet::new();
+    let mut strings = 0;
+    let mut labels: Vec<LabelInfo> = Vec::new();
+    let zero_is_null = zero_is_null(archive)?;
+    for addr in (0..archive.size()).step_by(4) {
+        match archive.read_pointer(addr)? {
+            Some(0) if zero_is_null => {
+                pointers += 1;
+                null_pointers += 1;
+            }
+            Some(ptr) => {
+                pointers += 1;
+                destinations.insert(ptr);
+            }
+            None => {}
+        }
+        if archive.read_string(addr)?.is_some() {
+            strings += 1;
+        }
+        if let Some(names) = archive.read_labels(addr)? {
+            labels.extend(names.into_iter().map(|name| LabelInfo {
+                address: addr,
+                name,
+            }));
+        }
+    }
+    if let Some(names) = archive.read_labels(archive.size())? {
+        labels.extend(names.into_iter().map(|name| LabelInfo {
+            address: archive.size(),
+            name,
+        }));
+    }
+    Ok(ArchiveInfo {
+        size: archive.size(),
+        pointers,
+        null_pointers,
+        destinations: destinations.len(),
+        strings,
+        labels,
+    })
+}
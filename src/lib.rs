@@ -1,3 +1,32 @@
+mod align;
+mod bps;
+mod diff;
+mod error;
+mod header;
+mod info;
+mod merge;
+mod model;
+mod patch;
+mod quote;
+mod schema;
+mod sha1;
+mod structured;
 mod unpacker;
+mod verify;
 
-pub use unpacker::{pack, unpack};
\ No newline at end of file
+pub use bps::{apply_bps, create_bps, is_bps};
+pub use diff::{diff, Change, Value};
+pub use error::{PackError, PackErrorKind, PackErrors, PackWarning, PackWarningKind};
+pub use header::{Compression, Header, FORMAT_VERSION};
+pub use info::{archive_info, ArchiveInfo, LabelInfo};
+pub use merge::{merge, Merged};
+pub use model::{Comment, Entry, FieldValue, UnpackedArchive};
+pub use patch::{apply_patch, make_patch, Patch};
+pub use schema::{Endian, ScalarType, Schema};
+pub use sha1::sha1_hex;
+pub use structured::{pack_json, pack_yaml, unpack_json, unpack_yaml};
+pub use unpacker::{
+    pack, pack_collecting, pack_with_options, reformat, unpack, unpack_with_options, PackOptions,
+    Packed, UnpackOptions,
+};
+pub use verify::{verify_roundtrip, Mismatch};
@@ -1,111 +1,899 @@
-mod unpacker;
+mod batch;
 
 use anyhow::Context;
-use clap::{AppSettings, ArgGroup, Clap};
-use std::path::{Path, PathBuf};
+use asset_pack_rs::{
+    ArchiveInfo, Compression, Endian, Header, PackError, PackErrors, PackOptions, PackWarning,
+    Packed, Patch, Schema, UnpackOptions,
+};
+use clap::{AppSettings, ArgEnum, Clap};
 use mila::BinArchive;
+use serde::Serialize;
+use std::ffi::OsStr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
+fn is_compressed_path(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new("lz"))
+}
 
-fn read_bin_archive_input(input_path: &Path) -> anyhow::Result<BinArchive> {
+fn read_bin_input(input_path: &Path) -> anyhow::Result<Vec<u8>> {
     let input = std::fs::read(input_path).context("Failed to read input file.")?;
-    let input = if let Some(extension) = input_path.extension() {
-        if "lz" == extension {
-            mila::LZ13CompressionFormat {}
-                .decompress(&input)
-                .context("Failed to LZ13 decompress input.")
-        } else {
-            Ok(input)
-        }
+    decompress_input(input_path, input)
+}
+
+fn decompress_input(input_path: &Path, input: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    if is_compressed_path(input_path) {
+        mila::LZ13CompressionFormat {}
+            .decompress(&input)
+            .context("Failed to LZ13 decompress input.")
     } else {
         Ok(input)
-    }?;
-    let archive = BinArchive::from_bytes(&input)
-        .context("Failed to deserialize bin archive.")?;
+    }
+}
+
+fn read_bin_archive_input(input_path: &Path) -> anyhow::Result<BinArchive> {
+    let input = read_bin_input(input_path)?;
+    let archive = BinArchive::from_bytes(&input).context("Failed to deserialize bin archive.")?;
     Ok(archive)
 }
 
-#[derive(Clap, Debug)]
-#[clap(version = "1.0", author = "thane98")]
-#[clap(setting = AppSettings::ColoredHelp)]
-#[clap(group = ArgGroup::new("command").required(true))]
-struct Arguments {
-    input: String,
+fn render_diagnostic(
+    level: &str,
+    message: &dyn std::fmt::Display,
+    path: &str,
+    line: usize,
+    text: &str,
+    span: &Range<usize>,
+) -> String {
+    let gutter = " ".repeat(line.to_string().len());
+    let column = text[..span.start].chars().count();
+    let width = text[span.clone()].chars().count().max(1);
+    format!(
+        "{}: {}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}",
+        level,
+        message,
+        gutter,
+        path,
+        line,
+        column + 1,
+        gutter,
+        line,
+        text,
+        gutter,
+        " ".repeat(column),
+        "^".repeat(width)
+    )
+}
 
-    #[clap(long, short)]
-    output: Option<String>,
+fn render_pack_error(error: &PackError, path: &str) -> String {
+    render_diagnostic(
+        "error",
+        &error.kind,
+        path,
+        error.line,
+        &error.text,
+        &error.span,
+    )
+}
 
-    #[clap(long, short, group = "command", about = "Unpack a bin file")]
-    unpack: bool,
+fn render_pack_warning(warning: &PackWarning, path: &str) -> String {
+    render_diagnostic(
+        "warning",
+        &warning.kind,
+        path,
+        warning.line,
+        &warning.text,
+        &warning.span,
+    )
+}
 
-    #[clap(long, short, group = "command", about = "Pack a text file")]
-    pack: bool,
+fn report_pack_errors(errors: &PackErrors, path: &str) {
+    for error in &errors.errors {
+        eprintln!("{}\n", render_pack_error(error, path));
+    }
+    if errors.total > errors.errors.len() {
+        eprintln!(
+            "... and {} more error(s)",
+            errors.total - errors.errors.len()
+        );
+    }
+    eprintln!("error: could not pack due to {} error(s)", errors.total);
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = Arguments::parse();
+fn unpack_file(
+    input_path: &Path,
+    output_path: &Path,
+    format: Format,
+    options: &UnpackOptions,
+) -> anyhow::Result<()> {
+    let input = read_bin_input(input_path)?;
+    let header = Header {
+        compression: Some(if is_compressed_path(input_path) {
+            Compression::Lz13
+        } else {
+            Compression::None
+        }),
+        source: input_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned()),
+        sha1: Some(asset_pack_rs::sha1_hex(&input)),
+        endian: options.header.endian,
+    };
+    let archive = BinArchive::from_bytes(&input).context("Failed to deserialize bin archive.")?;
+    let text = match format {
+        Format::Text => {
+            let options = UnpackOptions { header, ..*options };
+            asset_pack_rs::unpack_with_options(&archive, &options)
+        }
+        Format::Json => asset_pack_rs::unpack_json(&archive),
+        Format::Yaml => asset_pack_rs::unpack_yaml(&archive),
+    }
+    .context("Failed to unpack archive.")?;
 
-    let input_path = Path::new(&args.input);
+    std::fs::write(output_path, text).context("Failed to save output.")?;
+    Ok(())
+}
+
+// `explicit_output` is set when the output path was given on the command
+// line rather than derived from the input's name. Returns messages to report
+// for the file, which are printed by the caller so batch output keeps its
+// order.
+fn pack_file(
+    input_path: &Path,
+    output_path: &Path,
+    explicit_output: bool,
+    args: &PackArguments,
+) -> anyhow::Result<Vec<String>> {
+    let input = std::fs::read_to_string(input_path).context("Failed to read input file.")?;
+    let packed = match args.format {
+        Format::Text => asset_pack_rs::pack_with_options(
+            &input,
+            &PackOptions {
+                max_errors: args.max_errors.max(1),
+                endian: args.endian.map(Endian::from),
+                ..PackOptions::default()
+            },
+        ),
+        Format::Json => asset_pack_rs::pack_json(&input).map(Packed::from),
+        Format::Yaml => asset_pack_rs::pack_yaml(&input).map(Packed::from),
+    };
+    let packed = match packed {
+        Ok(packed) => packed,
+        Err(err) if err.is::<PackErrors>() => return Err(err),
+        Err(err) => return Err(err.context("Failed to pack input file.")),
+    };
+
+    let path = input_path.display().to_string();
+    let mut messages: Vec<String> = packed
+        .warnings
+        .iter()
+        .map(|warning| render_pack_warning(warning, &path))
+        .collect();
+
+    // The header records how the source was stored, which decides how a
+    // derived output name is written. An output path given on the command
+    // line is written as its extension says.
+    let header = &packed.header;
+    let compress = match header.compression {
+        Some(compression) if !explicit_output => compression == Compression::Lz13,
+        Some(compression) => {
+            let compress = is_compressed_path(output_path);
+            if compress != (compression == Compression::Lz13) {
+                messages.push(format!(
+                    "warning: Writing '{}' {}, although its header records {}",
+                    output_path.display(),
+                    if compress {
+                        "LZ13 compressed"
+                    } else {
+                        "uncompressed"
+                    },
+                    compression.name()
+                ));
+            }
+            compress
+        }
+        None => is_compressed_path(output_path),
+    };
+    let serialized = packed
+        .archive
+        .serialize()
+        .context("Failed to serialize bin archive.")?;
+    if let Some(sha1) = &header.sha1 {
+        let source = header.source.as_deref().unwrap_or("the source file");
+        if asset_pack_rs::sha1_hex(&serialized) == *sha1 {
+            messages.push(format!("note: Output is identical to {}", source));
+        } else {
+            messages.push(format!("note: Output differs from {}", source));
+        }
+    }
+    write_output_bytes(output_path, compress_output(serialized, compress)?)?;
+    Ok(messages)
+}
+
+fn write_bin_output(output_path: &Path, archive: &BinArchive) -> anyhow::Result<()> {
+    let serialized = archive
+        .serialize()
+        .context("Failed to serialize bin archive.")?;
+    write_bin_bytes(output_path, serialized)
+}
+
+fn write_bin_bytes(output_path: &Path, serialized: Vec<u8>) -> anyhow::Result<()> {
+    let bytes = compress_output(serialized, is_compressed_path(output_path))?;
+    write_output_bytes(output_path, bytes)
+}
+
+fn compress_output(serialized: Vec<u8>, compress: bool) -> anyhow::Result<Vec<u8>> {
+    if compress {
+        mila::LZ13CompressionFormat {}
+            .compress(&serialized)
+            .context("Failed to compress output.")
+    } else {
+        Ok(serialized)
+    }
+}
+
+fn write_output_bytes(output_path: &Path, bytes: Vec<u8>) -> anyhow::Result<()> {
+    log::debug!(
+        "Writing {} bytes to '{}'",
+        bytes.len(),
+        output_path.display()
+    );
+    std::fs::write(output_path, bytes).context("Failed to write output.")
+}
+
+fn run_batch(
+    input_dir: &Path,
+    output: Option<&str>,
+    jobs: Option<usize>,
+    quiet: bool,
+    output_name: impl Fn(&str) -> Option<String>,
+    process: impl Fn(&Path, &Path) -> anyhow::Result<Vec<String>> + Sync,
+) -> anyhow::Result<()> {
+    let output_dir = Path::new(
+        output.context("An output directory is required when the input is a directory.")?,
+    );
+    let summary = batch::run(input_dir, output_dir, jobs, output_name, process)?;
+
+    if !quiet {
+        for (_, messages) in &summary.succeeded {
+            for message in messages {
+                eprintln!("{}\n", message);
+            }
+        }
+        for path in &summary.skipped {
+            println!("Skipped '{}'", path.display());
+        }
+    }
+    for (path, err) in &summary.failed {
+        if let Some(errors) = err.downcast_ref::<PackErrors>() {
+            report_pack_errors(errors, &input_dir.join(path).display().to_string());
+        }
+        eprintln!("Failed '{}': {:#}", path.display(), err);
+    }
+    if !quiet {
+        println!(
+            "{} succeeded, {} failed, {} skipped",
+            summary.succeeded.len(),
+            summary.failed.len(),
+            summary.skipped.len()
+        );
+    }
+    if !summary.failed.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn validate_input_file(input_path: &Path) -> anyhow::Result<()> {
     if !input_path.exists() || !input_path.is_file() {
         return Err(anyhow::anyhow!(
             "Input is not a valid file: '{}'",
             input_path.display()
         ));
     }
+    Ok(())
+}
+
+fn run_unpack(args: &UnpackArguments, quiet: bool) -> anyhow::Result<()> {
+    let input_path = Path::new(&args.input);
+    if args.format != Format::Text
+        && (args.schema.is_some() || args.typed_words || args.endian.is_some())
+    {
+        return Err(anyhow::anyhow!(
+            "--schema, --typed-words and --endian are only supported with the text format."
+        ));
+    }
+    let schema = match &args.schema {
+        Some(path) => {
+            let text = std::fs::read_to_string(path).context("Failed to read schema.")?;
+            Some(Schema::from_yaml(&text).context("Failed to parse schema.")?)
+        }
+        None => None,
+    };
+    let options = UnpackOptions {
+        schema: schema.as_ref(),
+        typed_words: args.typed_words,
+        header: Header {
+            endian: args.endian.map(Endian::from).unwrap_or_default(),
+            ..Header::default()
+        },
+    };
+    if input_path.is_dir() {
+        let extension = format!(".{}", args.format.extension());
+        return run_batch(
+            input_path,
+            args.output.as_deref(),
+            args.jobs,
+            quiet,
+            |name| {
+                if name.ends_with(".bin") || name.ends_with(".bin.lz") {
+                    Some(format!("{}{}", name, extension))
+                } else {
+                    None
+                }
+            },
+            |input, output| unpack_file(input, output, args.format, &options).map(|_| Vec::new()),
+        );
+    }
+    validate_input_file(input_path)?;
 
-    if args.unpack {
-        let archive = read_bin_archive_input(&input_path)?;
-        let text = unpacker::unpack(&archive).context("Failed to unpack archive.")?;
+    let path = if let Some(path) = &args.output {
+        let mut buf = PathBuf::new();
+        buf.push(path);
+        buf
+    } else {
+        let mut filename = input_path
+            .file_name()
+            .context("Could not get filename from input")?
+            .to_owned();
+        filename.push(".");
+        filename.push(args.format.extension());
+        let mut buf = PathBuf::new();
+        buf.push(filename);
+        buf
+    };
 
-        let path = if let Some(path) = args.output {
-            let mut buf = PathBuf::new();
-            buf.push(path);
-            buf
-        } else {
-            let mut filename = input_path
+    unpack_file(input_path, &path, args.format, &options)
+}
+
+fn run_pack(args: &PackArguments, quiet: bool) -> anyhow::Result<()> {
+    let input_path = Path::new(&args.input);
+    if args.format != Format::Text && args.endian.is_some() {
+        return Err(anyhow::anyhow!(
+            "--endian is only supported with the text format."
+        ));
+    }
+    if input_path.is_dir() {
+        let extension = format!(".{}", args.format.extension());
+        return run_batch(
+            input_path,
+            args.output.as_deref(),
+            args.jobs,
+            quiet,
+            |name| name.strip_suffix(extension.as_str()).map(str::to_owned),
+            |input, output| pack_file(input, output, false, args),
+        );
+    }
+    validate_input_file(input_path)?;
+
+    let path = if let Some(path) = &args.output {
+        let mut buf = PathBuf::new();
+        buf.push(path);
+        buf
+    } else {
+        let mut buf = PathBuf::new();
+        buf.push(
+            input_path
                 .file_name()
-                .context("Could not get filename from input")?
-                .to_owned();
-            filename.push(".txt");
-            let mut buf = PathBuf::new();
-            buf.push(filename);
-            buf
-        };
-
-        std::fs::write(path, text).context("Failed to save output.")?;
-    } else if args.pack {
-        let input = std::fs::read_to_string(&args.input)
-            .context("Failed to read input file.")?;
-        let archive = unpacker::pack(&input).context("Failed to pack input file.")?;
-
-        let path = if let Some(path) = args.output {
-            let mut buf = PathBuf::new();
-            buf.push(path);
-            buf
-        } else {
-            let mut buf = PathBuf::new();
-            buf.push(
-                input_path
-                    .file_name()
-                    .context("Could not get filename from input")?,
-            );
-            buf.set_extension("");
-            buf
-        };
-        
-        let serialized = archive.serialize().context("Failed to serialize bin archive.")?;
-        let bytes = if let Some(extension) = path.extension() {
-            if "lz" == extension {
-                mila::LZ13CompressionFormat{}.compress(&serialized)
-                    .context("Failed to compress output.")?
-            } else {
-                serialized
+                .context("Could not get filename from input")?,
+        );
+        buf.set_extension("");
+        buf
+    };
+
+    match pack_file(input_path, &path, args.output.is_some(), args) {
+        Ok(messages) => {
+            if !quiet {
+                for message in &messages {
+                    eprintln!("{}\n", message);
+                }
             }
-        } else {
-            serialized
-        };
+            Ok(())
+        }
+        Err(err) => {
+            if let Some(errors) = err.downcast_ref::<PackErrors>() {
+                report_pack_errors(errors, &args.input);
+                std::process::exit(1);
+            }
+            Err(err)
+        }
+    }
+}
+
+fn run_verify(args: &VerifyArguments, quiet: bool) -> anyhow::Result<()> {
+    let input_path = Path::new(&args.input);
+    validate_input_file(input_path)?;
+
+    let input = read_bin_input(input_path)?;
+    match asset_pack_rs::verify_roundtrip(&input).context("Failed to round trip archive.")? {
+        Some(mismatch) => {
+            eprintln!("{}", mismatch);
+            std::process::exit(1);
+        }
+        None => {
+            if !quiet {
+                println!("'{}' round trips exactly.", input_path.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_diff(args: &DiffArguments, quiet: bool) -> anyhow::Result<()> {
+    let old_path = Path::new(&args.old);
+    let new_path = Path::new(&args.new);
+    validate_input_file(old_path)?;
+    validate_input_file(new_path)?;
+
+    let old = read_bin_archive_input(old_path)?;
+    let new = read_bin_archive_input(new_path)?;
+    let changes = asset_pack_rs::diff(&old, &new).context("Failed to compare archives.")?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&changes)?);
+    } else {
+        for change in &changes {
+            println!("{}", change);
+        }
+        if changes.is_empty() && !quiet {
+            println!("No differences.");
+        }
+    }
+    if !changes.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_merge(args: &MergeArguments, quiet: bool) -> anyhow::Result<()> {
+    let mut archives = Vec::new();
+    for input in &[&args.base, &args.ours, &args.theirs] {
+        let input_path = Path::new(input);
+        validate_input_file(input_path)?;
+        archives.push(read_bin_archive_input(input_path)?);
+    }
+    let merged = asset_pack_rs::merge(&archives[0], &archives[1], &archives[2])
+        .context("Failed to merge archives.")?;
+
+    match &args.output {
+        Some(output) => {
+            std::fs::write(output, &merged.text).context("Failed to write output file.")?
+        }
+        None => println!("{}", merged.text),
+    }
+    if merged.conflicts > 0 {
+        eprintln!(
+            "{} conflict(s) must be resolved before packing.",
+            merged.conflicts
+        );
+        std::process::exit(1);
+    }
+    if !quiet && args.output.is_some() {
+        println!("Merged without conflicts.");
+    }
+    Ok(())
+}
+
+fn run_make_patch(args: &MakePatchArguments, quiet: bool) -> anyhow::Result<()> {
+    let original_path = Path::new(&args.original);
+    let edited_path = Path::new(&args.edited);
+    validate_input_file(original_path)?;
+    validate_input_file(edited_path)?;
+
+    if args.bps {
+        let original = read_bin_input(original_path)?;
+        let edited = read_bin_input(edited_path)?;
+        let patch = asset_pack_rs::create_bps(&original, &edited);
+        std::fs::write(&args.output, patch).context("Failed to write patch.")?;
+        if !quiet {
+            println!("Wrote BPS patch to '{}'.", args.output);
+        }
+        return Ok(());
+    }
+
+    let original = read_bin_archive_input(original_path)?;
+    let edited = read_bin_archive_input(edited_path)?;
+    let patch =
+        asset_pack_rs::make_patch(&original, &edited).context("Failed to compare archives.")?;
+    let json = serde_json::to_string_pretty(&patch)?;
+    std::fs::write(&args.output, json).context("Failed to write patch.")?;
+    if !quiet {
+        println!("Wrote {} change(s) to '{}'.", patch.len(), args.output);
+    }
+    Ok(())
+}
 
-        std::fs::write(path, bytes).context("Failed to write output.")?;
+fn run_apply_patch(args: &ApplyPatchArguments, quiet: bool) -> anyhow::Result<()> {
+    let original_path = Path::new(&args.original);
+    let patch_path = Path::new(&args.patch);
+    validate_input_file(original_path)?;
+    validate_input_file(patch_path)?;
+
+    let output_path = args.output.as_deref().map_or(original_path, Path::new);
+    let patch = std::fs::read(patch_path).context("Failed to read patch.")?;
+    if asset_pack_rs::is_bps(&patch) {
+        let original = read_bin_input(original_path)?;
+        let patched =
+            asset_pack_rs::apply_bps(&original, &patch).context("Failed to apply patch.")?;
+        write_bin_bytes(output_path, patched)?;
+        if !quiet {
+            println!("Applied BPS patch to '{}'.", output_path.display());
+        }
+        return Ok(());
+    }
+
+    let original = read_bin_archive_input(original_path)?;
+    let patch: Patch = serde_json::from_slice(&patch).context("Failed to parse patch.")?;
+    let patched =
+        asset_pack_rs::apply_patch(&original, &patch).context("Failed to apply patch.")?;
+    write_bin_output(output_path, &patched)?;
+    if !quiet {
+        println!(
+            "Applied {} change(s) to '{}'.",
+            patch.len(),
+            output_path.display()
+        );
+    }
+    Ok(())
+}
+
+fn run_reformat(args: &ReformatArguments, quiet: bool) -> anyhow::Result<()> {
+    let input_path = Path::new(&args.input);
+    validate_input_file(input_path)?;
+
+    let output_path = args.output.as_deref().map_or(input_path, Path::new);
+    let input = std::fs::read_to_string(input_path).context("Failed to read input file.")?;
+    let options = PackOptions {
+        max_errors: args.max_errors.max(1),
+        ..PackOptions::default()
+    };
+    let text = match asset_pack_rs::reformat(&input, &options) {
+        Ok(text) => text,
+        Err(err) => {
+            if let Some(errors) = err.downcast_ref::<PackErrors>() {
+                report_pack_errors(errors, &args.input);
+                std::process::exit(1);
+            }
+            return Err(err.context("Failed to reformat input file."));
+        }
+    };
+    std::fs::write(output_path, text).context("Failed to save output.")?;
+    if !quiet {
+        println!("Reformatted '{}'.", output_path.display());
     }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct FileInfo<'a> {
+    path: &'a str,
+    file_size: u64,
+    compressed: bool,
+    #[serde(flatten)]
+    archive: ArchiveInfo,
+}
 
+fn run_info(args: &InfoArguments) -> anyhow::Result<()> {
+    let input_path = Path::new(&args.input);
+    validate_input_file(input_path)?;
+
+    let archive = read_bin_archive_input(input_path)?;
+    let info = FileInfo {
+        path: &args.input,
+        file_size: std::fs::metadata(input_path)?.len(),
+        compressed: is_compressed_path(input_path),
+        archive: asset_pack_rs::archive_info(&archive)?,
+    };
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+    println!("File:         {}", info.path);
+    println!(
+        "File size:    {} bytes{}",
+        info.file_size,
+        if info.compressed {
+            " (LZ13 compressed)"
+        } else {
+            ""
+        }
+    );
+    println!("Data size:    {} bytes", info.archive.size);
+    println!(
+        "Pointers:     {} ({} null)",
+        info.archive.pointers, info.archive.null_pointers
+    );
+    println!("Destinations: {}", info.archive.destinations);
+    println!("Strings:      {}", info.archive.strings);
+    println!("Labels:       {}", info.archive.labels.len());
+    for label in &info.archive.labels {
+        println!("  0x{:08X}  {}", label.address, label.name);
+    }
     Ok(())
 }
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Json,
+    Yaml,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Text => "txt",
+            Format::Json => "json",
+            Format::Yaml => "yaml",
+        }
+    }
+}
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq)]
+enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl From<ByteOrder> for Endian {
+    fn from(order: ByteOrder) -> Self {
+        match order {
+            ByteOrder::Little => Endian::Little,
+            ByteOrder::Big => Endian::Big,
+        }
+    }
+}
+
+#[derive(Clap, Debug)]
+#[clap(version = "1.0", author = "thane98")]
+#[clap(setting = AppSettings::ColoredHelp)]
+#[clap(setting = AppSettings::SubcommandRequiredElseHelp)]
+struct Arguments {
+    #[clap(
+        long,
+        short,
+        global = true,
+        conflicts_with = "quiet",
+        about = "Log pointer resolution, label placement and output size"
+    )]
+    verbose: bool,
+
+    #[clap(long, short, global = true, about = "Only print errors")]
+    quiet: bool,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Clap, Debug)]
+enum Command {
+    #[clap(short_flag = 'u', long_flag = "unpack", about = "Unpack a bin file")]
+    Unpack(UnpackArguments),
+
+    #[clap(short_flag = 'p', long_flag = "pack", about = "Pack a text file")]
+    Pack(PackArguments),
+
+    #[clap(
+        long_flag = "verify",
+        about = "Check that a bin file survives unpacking and repacking"
+    )]
+    Verify(VerifyArguments),
+
+    #[clap(about = "Summarize the contents of a bin file")]
+    Info(InfoArguments),
+
+    #[clap(about = "Compare two bin files, exiting with 1 if they differ")]
+    Diff(DiffArguments),
+
+    #[clap(about = "Merge the changes two bin files made to a common base into a text file")]
+    Merge(MergeArguments),
+
+    #[clap(about = "Record the changes between two bin files in a patch")]
+    MakePatch(MakePatchArguments),
+
+    #[clap(about = "Apply a patch or BPS patch to a bin file, in place unless --output is given")]
+    ApplyPatch(ApplyPatchArguments),
+
+    #[clap(about = "Rewrite a text file in canonical form, in place unless --output is given")]
+    Reformat(ReformatArguments),
+}
+
+#[derive(Clap, Debug)]
+struct UnpackArguments {
+    #[clap(about = "Bin file, or a directory to unpack recursively")]
+    input: String,
+
+    #[clap(long, short)]
+    output: Option<String>,
+
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "text",
+        about = "Format of the unpacked file"
+    )]
+    format: Format,
+
+    #[clap(long, about = "YAML file describing structs to decode at labels")]
+    schema: Option<String>,
+
+    #[clap(
+        long,
+        about = "Show raw words that look like numbers as U32/I16/U8/F32 directives"
+    )]
+    typed_words: bool,
+
+    #[clap(
+        long,
+        arg_enum,
+        about = "Byte order of fields and typed words [default: little]"
+    )]
+    endian: Option<ByteOrder>,
+
+    #[clap(
+        long,
+        short,
+        about = "Number of files to process at once in directory mode [default: all cores]"
+    )]
+    jobs: Option<usize>,
+}
+
+#[derive(Clap, Debug)]
+struct PackArguments {
+    #[clap(about = "Unpacked file, or a directory to pack recursively")]
+    input: String,
+
+    #[clap(long, short)]
+    output: Option<String>,
+
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "text",
+        about = "Format of the unpacked file"
+    )]
+    format: Format,
+
+    #[clap(
+        long,
+        default_value = "100",
+        about = "Maximum number of errors to report"
+    )]
+    max_errors: usize,
+
+    #[clap(
+        long,
+        arg_enum,
+        about = "Byte order of fields and typed words [default: from the file, or little]"
+    )]
+    endian: Option<ByteOrder>,
+
+    #[clap(
+        long,
+        short,
+        about = "Number of files to process at once in directory mode [default: all cores]"
+    )]
+    jobs: Option<usize>,
+}
+
+#[derive(Clap, Debug)]
+struct VerifyArguments {
+    input: String,
+}
+
+#[derive(Clap, Debug)]
+struct DiffArguments {
+    old: String,
+    new: String,
+
+    #[clap(long, about = "Print the changes as JSON")]
+    json: bool,
+}
+
+#[derive(Clap, Debug)]
+struct MergeArguments {
+    base: String,
+    ours: String,
+    theirs: String,
+
+    #[clap(long, short, about = "Text file to write, instead of standard output")]
+    output: Option<String>,
+}
+
+#[derive(Clap, Debug)]
+struct MakePatchArguments {
+    original: String,
+    edited: String,
+
+    #[clap(long, short)]
+    output: String,
+
+    #[clap(long, about = "Write a BPS patch of the serialized archives")]
+    bps: bool,
+}
+
+#[derive(Clap, Debug)]
+struct ApplyPatchArguments {
+    original: String,
+    patch: String,
+
+    #[clap(long, short)]
+    output: Option<String>,
+}
+
+#[derive(Clap, Debug)]
+struct ReformatArguments {
+    input: String,
+
+    #[clap(long, short)]
+    output: Option<String>,
+
+    #[clap(
+        long,
+        default_value = "100",
+        about = "Maximum number of errors to report"
+    )]
+    max_errors: usize,
+}
+
+#[derive(Clap, Debug)]
+struct InfoArguments {
+    input: String,
+
+    #[clap(long, about = "Print the summary as JSON")]
+    json: bool,
+}
+
+// Older versions took `<input> --unpack` style flags in any order. Move a
+// command flag found after the input to the front so it parses as a
+// subcommand.
+fn legacy_args() -> Vec<String> {
+    let mut args: Vec<String> = std::env::args().collect();
+    let is_command_flag =
+        |arg: &String| ["-u", "--unpack", "-p", "--pack", "--verify"].contains(&arg.as_str());
+    if args.len() > 2 && !is_command_flag(&args[1]) {
+        if let Some(index) = args[2..].iter().position(is_command_flag) {
+            let flag = args.remove(index + 2);
+            args.insert(1, flag);
+        }
+    }
+    args
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Arguments::parse_from(legacy_args());
+
+    let level = if args.verbose {
+        log::LevelFilter::Debug
+    } else if args.quiet {
+        log::LevelFilter::Error
+    } else {
+        log::LevelFilter::Warn
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .format_timestamp(None)
+        .init();
+
+    match &args.command {
+        Command::Unpack(command) => run_unpack(command, args.quiet),
+        Command::Pack(command) => run_pack(command, args.quiet),
+        Command::Verify(command) => run_verify(command, args.quiet),
+        Command::Info(command) => run_info(command),
+        Command::Diff(command) => run_diff(command, args.quiet),
+        Command::Merge(command) => run_merge(command, args.quiet),
+        Command::MakePatch(command) => run_make_patch(command, args.quiet),
+        Command::ApplyPatch(command) => run_apply_patch(command, args.quiet),
+        Command::Reformat(command) => run_reformat(command, args.quiet),
+    }
+}
@@ -0,0 +1,537 @@
+use crate::align::{sections, Alignment, Section, SectionKey, Slot, Target};
+use crate::diff::relative_to;
+use crate::header::Header;
+use crate::model::{Entry, UnpackedArchive, NULL_POINTER};
+use crate::unpacker::parse_word;
+use mila::BinArchive;
+use std::collections::{HashMap, HashSet};
+
+/// Markers written around conflicting regions of a merged text file.
+pub const CONFLICT_START: &str = "<<<<<<< ours";
+pub const CONFLICT_SEPARATOR: &str = "=======";
+pub const CONFLICT_END: &str = ">>>>>>> theirs";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Side {
+    Base,
+    Ours,
+    Theirs,
+}
+
+// A slot of one version: its side, section and position.
+type Id<'a> = (Side, &'a SectionKey, usize);
+
+// A pointer's destination: the slot written for its target and an offset
+// into it, or `None` for the end of the data.
+type Destination<'a> = Option<(Id<'a>, usize)>;
+
+#[derive(Clone, Copy)]
+struct Piece<'a> {
+    side: Side,
+    section: &'a Section,
+    position: usize,
+}
+
+impl<'a> Piece<'a> {
+    fn id(&self) -> Id<'a> {
+        (self.side, &self.section.key, self.position)
+    }
+
+    fn slot(&self) -> &'a Slot {
+        &self.section.slots[self.position]
+    }
+
+    fn is_label(&self) -> bool {
+        matches!(self.slot(), Slot::Label(_))
+    }
+}
+
+fn pieces(side: Side, section: Option<&Section>) -> Vec<Piece<'_>> {
+    section.map_or_else(Vec::new, |section| {
+        (0..section.slots.len())
+            .map(|position| Piece {
+                side,
+                section,
+                position,
+            })
+            .collect()
+    })
+}
+
+enum Chunk<'a> {
+    Clean(Vec<Piece<'a>>),
+    Conflict(Vec<Piece<'a>>, Vec<Piece<'a>>),
+}
+
+/// A run of merged entries, or both sides of a conflicting run.
+pub(crate) enum Block {
+    Clean(Vec<Entry>),
+    Conflict {
+        ours: Vec<Entry>,
+        theirs: Vec<Entry>,
+    },
+}
+
+/// The result of a three-way merge, as text in the unpacked format.
+///
+/// If `conflicts` is non-zero, the text contains conflict markers that must
+/// be resolved by hand before it can be packed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Merged {
+    pub text: String,
+    pub conflicts: usize,
+}
+
+struct Merger<'a> {
+    ours: Alignment<'a>,
+    theirs: Alignment<'a>,
+    chunks: Vec<Chunk<'a>>,
+    // Every slot that was written, or whose counterpart was, mapped to the
+    // slot written in its place.
+    written: HashMap<Id<'a>, Piece<'a>>,
+}
+
+impl<'a> Merger<'a> {
+    fn to_base(&self, side: Side, target: &Target) -> Option<Target> {
+        match side {
+            Side::Base => Some(target.clone()),
+            Side::Ours => self.ours.target_backward(target),
+            Side::Theirs => self.theirs.target_backward(target),
+        }
+    }
+
+    // Pointers agree if they target the same word of the base, or the same
+    // new word on the same side.
+    fn same(&self, a: Piece, b: Piece) -> bool {
+        match (a.slot(), b.slot()) {
+            (Slot::Pointer(x), Slot::Pointer(y)) => {
+                match (self.to_base(a.side, x), self.to_base(b.side, y)) {
+                    (Some(x), Some(y)) => x == y,
+                    (None, None) => a.side == b.side && x == y,
+                    _ => false,
+                }
+            }
+            (x, y) => x == y,
+        }
+    }
+
+    fn same_all(&self, a: &[Piece], b: &[Piece]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(a, b)| self.same(*a, *b))
+    }
+
+    // Merges one run of each version. `aligned` runs are a single slot that
+    // all three versions share.
+    fn resolve(
+        &mut self,
+        base: &[Piece<'a>],
+        ours: &[Piece<'a>],
+        theirs: &[Piece<'a>],
+        aligned: bool,
+    ) {
+        if base.is_empty() && ours.is_empty() && theirs.is_empty() {
+            return;
+        }
+        let chosen = if self.same_all(theirs, base) {
+            ours
+        } else if self.same_all(ours, base) {
+            theirs
+        } else if self.same_all(ours, theirs) {
+            ours
+        } else {
+            for piece in ours.iter().chain(theirs) {
+                self.written.insert(piece.id(), *piece);
+            }
+            if aligned {
+                self.written.insert(base[0].id(), ours[0]);
+            }
+            match self.chunks.last_mut() {
+                Some(Chunk::Conflict(our_side, their_side)) => {
+                    our_side.extend_from_slice(ours);
+                    their_side.extend_from_slice(theirs);
+                }
+                _ => self
+                    .chunks
+                    .push(Chunk::Conflict(ours.to_vec(), theirs.to_vec())),
+            }
+            return;
+        };
+        for piece in chosen {
+            self.written.insert(piece.id(), *piece);
+        }
+        for other in [base, ours, theirs].iter() {
+            if aligned || self.same_all(other, chosen) {
+                for (piece, written) in other.iter().zip(chosen) {
+                    if piece.is_label() == written.is_label() {
+                        self.written.entry(piece.id()).or_insert(*written);
+                    }
+                }
+            }
+        }
+        match self.chunks.last_mut() {
+            Some(Chunk::Clean(pieces)) => pieces.extend_from_slice(chosen),
+            _ => self.chunks.push(Chunk::Clean(chosen.to_vec())),
+        }
+    }
+
+    // A three-way merge of one section, in the manner of diff3: slots the
+    // base shares with both sides split the section into runs, and each run
+    // takes whichever side changed it.
+    fn merge_section(
+        &mut self,
+        base: Option<&'a Section>,
+        ours: Option<&'a Section>,
+        theirs: Option<&'a Section>,
+    ) {
+        let base = pieces(Side::Base, base);
+        let ours = pieces(Side::Ours, ours);
+        let theirs = pieces(Side::Theirs, theirs);
+        let (mut b, mut o, mut t) = (0, 0, 0);
+        for (index, piece) in base.iter().enumerate() {
+            let key = &piece.section.key;
+            if let (Some(our_index), Some(their_index)) = (
+                self.ours.forward(key, index),
+                self.theirs.forward(key, index),
+            ) {
+                self.resolve(
+                    &base[b..index],
+                    &ours[o..our_index],
+                    &theirs[t..their_index],
+                    false,
+                );
+                self.resolve(
+                    &base[index..=index],
+                    &ours[our_index..=our_index],
+                    &theirs[their_index..=their_index],
+                    true,
+                );
+                b = index + 1;
+                o = our_index + 1;
+                t = their_index + 1;
+            }
+        }
+        self.resolve(&base[b..], &ours[o..], &theirs[t..], false);
+    }
+
+    // The written slot a pointer targets, and the offset into it, or `None`
+    // for the end of the data.
+    fn destination(&self, piece: Piece<'a>) -> anyhow::Result<Option<(Piece<'a>, usize)>> {
+        let (section, position, offset) = match piece.slot() {
+            Slot::Pointer(Target::Word {
+                section,
+                position,
+                offset,
+            }) => (section, *position, *offset),
+            _ => return Ok(None),
+        };
+        let written = self
+            .written
+            .get(&(piece.side, section, position))
+            .or_else(|| {
+                let position = match piece.side {
+                    Side::Base => Some(position),
+                    Side::Ours => self.ours.backward(section, position),
+                    Side::Theirs => self.theirs.backward(section, position),
+                }?;
+                self.written.get(&(Side::Base, section, position))
+            });
+        match written {
+            Some(written) if !written.is_label() => Ok(Some((*written, offset))),
+            _ => Err(anyhow::anyhow!(
+                "Pointer at {} targets data that was removed on the other side",
+                piece.section.location(piece.position)
+            )),
+        }
+    }
+
+    // Where each written word ends up in the merged output, relative to the
+    // label before it. Both sides of a conflict start from the same place.
+    fn locations(&self) -> HashMap<Id<'a>, String> {
+        type Cursor<'b> = (Option<(usize, &'b str)>, usize);
+        let mut locations = HashMap::new();
+        let mut walk = |pieces: &[Piece<'a>], (mut anchor, mut addr): Cursor<'a>| {
+            for piece in pieces {
+                match piece.slot() {
+                    Slot::Label(name) => {
+                        if anchor.map(|(start, _)| start) != Some(addr) {
+                            anchor = Some((addr, name.as_str()));
+                        }
+                    }
+                    _ => {
+                        locations.insert(piece.id(), relative_to(anchor.as_ref(), addr));
+                        addr += 4;
+                    }
+                }
+            }
+            (anchor, addr)
+        };
+        let mut cursor: Cursor = (None, 0);
+        for chunk in &self.chunks {
+            match chunk {
+                Chunk::Clean(pieces) => cursor = walk(pieces, cursor),
+                Chunk::Conflict(ours, theirs) => {
+                    walk(theirs, cursor);
+                    cursor = walk(ours, cursor);
+                }
+            }
+        }
+        locations
+    }
+
+    fn blocks(self) -> anyhow::Result<Vec<Block>> {
+        let locations = self.locations();
+        let mut destinations: HashMap<Id, Destination> = HashMap::new();
+        let mut targets: Vec<(Destination, String)> = Vec::new();
+        for chunk in &self.chunks {
+            let pieces = match chunk {
+                Chunk::Clean(pieces) => pieces.iter().chain(&[]),
+                Chunk::Conflict(ours, theirs) => ours.iter().chain(theirs),
+            };
+            for piece in pieces {
+                if let Slot::Pointer(_) = piece.slot() {
+                    let target = self.destination(*piece)?;
+                    let destination = target.map(|(written, offset)| (written.id(), offset));
+                    if !targets.iter().any(|(known, _)| *known == destination) {
+                        let name = match target {
+                            Some((written, 0)) => locations[&written.id()].clone(),
+                            Some((written, offset)) => {
+                                format!("{}+{}", locations[&written.id()], offset)
+                            }
+                            None => "end".to_owned(),
+                        };
+                        targets.push((destination, name));
+                    }
+                    destinations.insert(piece.id(), destination);
+                }
+            }
+        }
+
+        // Destinations are named after the location they target, with
+        // whitespace replaced so the name reads back as a single word.
+        let mut names: HashMap<Destination, String> = HashMap::new();
+        let mut used: HashSet<String> = HashSet::new();
+        used.insert(NULL_POINTER.to_owned());
+        for (destination, name) in targets {
+            let mut name = name.replace(char::is_whitespace, "_");
+            while used.contains(&name) {
+                name.push('_');
+            }
+            used.insert(name.clone());
+            names.insert(destination, name);
+        }
+
+        let to_entries = |pieces: &[Piece<'a>]| -> anyhow::Result<Vec<Entry>> {
+            let mut entries = Vec::new();
+            for piece in pieces {
+                if !piece.is_label() {
+                    for offset in 0..4 {
+                        if let Some(name) = names.get(&Some((piece.id(), offset))) {
+                            entries.push(Entry::PointerDest(name.clone(), offset));
+                        }
+                    }
+                }
+                entries.push(match piece.slot() {
+                    Slot::Label(name) => Entry::Label(name.clone()),
+                    Slot::Pointer(_) => {
+                        Entry::PointerSource(names[&destinations[&piece.id()]].clone())
+                    }
+                    Slot::Null => Entry::NullPointer,
+                    Slot::String(text) => Entry::Text(text.clone()),
+                    Slot::Raw(data) => Entry::Word(parse_word(data)?),
+                });
+            }
+            Ok(entries)
+        };
+        let mut blocks = Vec::new();
+        for chunk in &self.chunks {
+            blocks.push(match chunk {
+                Chunk::Clean(pieces) => Block::Clean(to_entries(pieces)?),
+                Chunk::Conflict(ours, theirs) => Block::Conflict {
+                    ours: to_entries(ours)?,
+                    theirs: to_entries(theirs)?,
+                },
+            });
+        }
+        if let Some(name) = names.get(&None) {
+            blocks.push(Block::Clean(vec![Entry::PointerDest(name.clone(), 0)]));
+        }
+        Ok(blocks)
+    }
+}
+
+/// Merges the sections of three versions of an archive.
+pub(crate) fn merge_sections<'a>(
+    base: &'a [Section],
+    ours: &'a [Section],
+    theirs: &'a [Section],
+) -> anyhow::Result<Vec<Block>> {
+    let by_key = |sections: &'a [Section]| -> HashMap<&'a SectionKey, &'a Section> {
+        sections
+            .iter()
+            .map(|section| (&section.key, section))
+            .collect()
+    };
+    let base_sections = by_key(base);
+    let our_sections = by_key(ours);
+    let their_sections = by_key(theirs);
+
+    // Keep our order, placing sections only they have after the section
+    // that precedes them on their side.
+    let mut inserted: HashMap<Option<&SectionKey>, Vec<&SectionKey>> = HashMap::new();
+    let mut previous: Option<&SectionKey> = None;
+    for section in theirs {
+        if our_sections.contains_key(&section.key) {
+            previous = Some(&section.key);
+        } else {
+            inserted.entry(previous).or_default().push(&section.key);
+        }
+    }
+    let mut order: Vec<&SectionKey> = inserted.remove(&None).unwrap_or_default();
+    for section in ours {
+        order.push(&section.key);
+        order.extend(inserted.remove(&Some(&section.key)).unwrap_or_default());
+    }
+
+    let mut merger = Merger {
+        ours: Alignment::new(base, ours),
+        theirs: Alignment::new(base, theirs),
+        chunks: Vec::new(),
+        written: HashMap::new(),
+    };
+    for key in order {
+        merger.merge_section(
+            base_sections.get(key).copied(),
+            our_sections.get(key).copied(),
+            their_sections.get(key).copied(),
+        );
+    }
+    merger.blocks()
+}
+
+fn push_text(lines: &mut Vec<String>, entries: Vec<Entry>) {
+    if !entries.is_empty() {
+        let unpacked = UnpackedArchive {
+            entries,
+            ..UnpackedArchive::default()
+        };
+        lines.push(unpacked.body_text());
+    }
+}
+
+/// Merges the changes made in `ours` and `theirs` since `base`.
+///
+/// The words after each label are aligned with the base as a sequence, as
+/// in `diff`, so an insertion on one side does not shift the words the
+/// other side changed. A run of words changed on only one side takes that
+/// side's version. Runs changed differently on both sides, including one
+/// side removing words the other changed, become conflicts.
+pub fn merge(base: &BinArchive, ours: &BinArchive, theirs: &BinArchive) -> anyhow::Result<Merged> {
+    let base = sections(&UnpackedArchive::from_archive(base)?)?;
+    let ours = sections(&UnpackedArchive::from_archive(ours)?)?;
+    let theirs = sections(&UnpackedArchive::from_archive(theirs)?)?;
+    let mut lines: Vec<String> = Header::default().lines();
+    let mut conflicts = 0;
+    for block in merge_sections(&base, &ours, &theirs)? {
+        match block {
+            Block::Clean(entries) => push_text(&mut lines, entries),
+            Block::Conflict { ours, theirs } => {
+                conflicts += 1;
+                lines.push(CONFLICT_START.to_owned());
+                push_text(&mut lines, ours);
+                lines.push(CONFLICT_SEPARATOR.to_owned());
+                push_text(&mut lines, theirs);
+                lines.push(CONFLICT_END.to_owned());
+            }
+        }
+    }
+    Ok(Merged {
+        text: lines.join("\n"),
+        conflicts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unpacker::pack;
+
+    fn merge_text(base: &str, ours: &str, theirs: &str) -> Merged {
+        merge(
+            &pack(base).unwrap(),
+            &pack(ours).unwrap(),
+            &pack(theirs).unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn body(merged: &Merged) -> Vec<&str> {
+        merged
+            .text
+            .lines()
+            .skip(Header::default().lines().len())
+            .collect()
+    }
+
+    #[test]
+    fn insertion_does_not_shift_the_other_side() {
+        let merged = merge_text(
+            "LABEL: A\n0x00000001\n0x00000002\n0x00000003",
+            "LABEL: A\n0x00000007\n0x00000001\n0x00000002\n0x00000003",
+            "LABEL: A\n0x00000001\n0x00000002\n0x00000009",
+        );
+        assert_eq!(merged.conflicts, 0);
+        assert_eq!(
+            body(&merged),
+            vec![
+                "LABEL: A",
+                "0x00000007",
+                "0x00000001",
+                "0x00000002",
+                "0x00000009"
+            ]
+        );
+    }
+
+    #[test]
+    fn different_changes_conflict() {
+        let merged = merge_text(
+            "LABEL: A\n0x00000001\n0x00000002",
+            "LABEL: A\n0x00000001\n0x00000005",
+            "LABEL: A\n0x00000001\n0x00000008",
+        );
+        assert_eq!(merged.conflicts, 1);
+        assert_eq!(
+            body(&merged),
+            vec![
+                "LABEL: A",
+                "0x00000001",
+                CONFLICT_START,
+                "0x00000005",
+                CONFLICT_SEPARATOR,
+                "0x00000008",
+                CONFLICT_END
+            ]
+        );
+    }
+
+    #[test]
+    fn pointers_follow_words_that_moved() {
+        let merged = merge_text(
+            "LABEL: A\nSRC: p\nLABEL: B\n0x00000001\nDEST: p\n0x00000002",
+            "LABEL: A\nSRC: p\nLABEL: B\n0x00000000\n0x00000001\nDEST: p\n0x00000002",
+            "LABEL: A\nSRC: p\nLABEL: B\n0x00000005\nDEST: p\n0x00000002",
+        );
+        assert_eq!(merged.conflicts, 0);
+        assert_eq!(
+            body(&merged),
+            vec![
+                "LABEL: A",
+                "SRC: B+0x8",
+                "LABEL: B",
+                "0x00000000",
+                "0x00000005",
+                "DEST: B+0x8",
+                "0x00000002"
+            ]
+        );
+    }
+}
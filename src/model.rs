@@ -0,0 +1,294 @@
+use crate::header::Header;
+use crate::schema::ScalarType;
+use crate::unpacker::reads_as_name;
+use mila::{BinArchive, BinArchiveWriter};
+use std::collections::{HashMap, HashSet};
+
+/// A single item of an unpacked archive.
+///
+/// `Word`, `Text`, `PointerSource` and `NullPointer` each occupy 4 bytes of
+/// the archive. `PointerDest` and `Label` are markers attached to the next
+/// word. A `PointerDest` carries a byte offset from the start of that word.
+/// A `Field` is data decoded by a schema and may be any size. A `Typed` word
+/// is a raw word displayed as one or more numbers of the given type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry {
+    Word([u8; 4]),
+    Text(String),
+    PointerSource(String),
+    NullPointer,
+    PointerDest(String, usize),
+    Label(String),
+    Field(String, FieldValue),
+    Typed(ScalarType, [u8; 4]),
+}
+
+/// The value of a named field decoded by a schema.
+///
+/// `Numbers` holds the raw bytes of one or more values of the given type.
+/// Strings and pointers take 4 bytes, like `Text` and `PointerSource`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Numbers(ScalarType, Vec<u8>),
+    String(String),
+    Pointer(String),
+}
+
+/// Name used for null pointers in place of a destination name.
+pub const NULL_POINTER: &str = "null";
+
+impl Entry {
+    pub fn size(&self) -> usize {
+        match self {
+            Entry::Word(_)
+            | Entry::Typed(_, _)
+            | Entry::Text(_)
+            | Entry::PointerSource(_)
+            | Entry::NullPointer => 4,
+            Entry::PointerDest(_, _) | Entry::Label(_) => 0,
+            Entry::Field(_, FieldValue::Numbers(_, bytes)) => bytes.len(),
+            Entry::Field(_, _) => 4,
+        }
+    }
+}
+
+/// A comment kept from a text file.
+///
+/// `index` is the number of entries before the comment. A trailing comment
+/// shares a line with the entry before it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    pub index: usize,
+    pub text: String,
+    pub trailing: bool,
+}
+
+/// An archive as an ordered list of entries, laid out from address 0.
+///
+/// `comments` are only kept when parsing text for `reformat`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UnpackedArchive {
+    pub entries: Vec<Entry>,
+    pub header: Header,
+    pub comments: Vec<Comment>,
+}
+
+/// Indices of destinations whose offset falls outside the entry they mark:
+/// the next entry with data, or the end of the data if none follows.
+pub(crate) fn misplaced_destinations(entries: &[Entry]) -> Vec<usize> {
+    let mut misplaced = Vec::new();
+    let mut pending: Vec<usize> = Vec::new();
+    let offset_of = |index: usize| match &entries[index] {
+        Entry::PointerDest(_, offset) => *offset,
+        _ => 0,
+    };
+    for (index, entry) in entries.iter().enumerate() {
+        match entry {
+            Entry::PointerDest(_, _) => pending.push(index),
+            Entry::Label(_) => {}
+            entry => misplaced.extend(
+                pending
+                    .drain(..)
+                    .filter(|dest| offset_of(*dest) >= entry.size()),
+            ),
+        }
+    }
+    misplaced.extend(pending.into_iter().filter(|dest| offset_of(*dest) > 0));
+    misplaced.sort_unstable();
+    misplaced
+}
+
+/// Whether pointer table entries targeting address 0 are null pointers.
+///
+/// The pointer table cannot tell a null pointer from a pointer to the first
+/// word. They are read as null unless a label marks address 0, in which case
+/// they point at it.
+pub(crate) fn zero_is_null(archive: &BinArchive) -> anyhow::Result<bool> {
+    Ok(archive.read_labels(0)?.is_none())
+}
+
+impl UnpackedArchive {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn size(&self) -> usize {
+        self.entries.iter().map(Entry::size).sum()
+    }
+
+    pub fn from_archive(archive: &BinArchive) -> anyhow::Result<Self> {
+        // Everything is laid out in 4-byte words, so pointers and labels
+        // can only be represented at aligned addresses. Pointers may target
+        // any byte up to and including the end of the data.
+        let mut pointers: HashMap<usize, usize> = HashMap::new();
+        for addr in 0..archive.size().saturating_sub(3) {
+            if let Some(ptr) = archive.read_pointer(addr)? {
+                if addr % 4 != 0 {
+                    return Err(anyhow::anyhow!(
+                        "Pointer at unaligned address 0x{:X} cannot be represented",
+                        addr
+                    ));
+                }
+                if ptr > archive.size() {
+                    return Err(anyhow::anyhow!(
+                        "Pointer at 0x{:X} targets 0x{:X}, past the end of the archive",
+                        addr,
+                        ptr
+                    ));
+                }
+                pointers.insert(addr, ptr);
+            }
+            if addr % 4 != 0 && archive.read_labels(addr)?.is_some() {
+                return Err(anyhow::anyhow!(
+                    "Label at unaligned address 0x{:X} cannot be represented",
+                    addr
+                ));
+            }
+        }
+
+        let zero_is_null = zero_is_null(archive)?;
+        let mut targets: Vec<usize> = pointers
+            .values()
+            .copied()
+            .filter(|ptr| *ptr != 0 || !zero_is_null)
+            .collect();
+        targets.sort_unstable();
+        targets.dedup();
+
+        // Destinations take the name of a label at the target if one is free,
+        // otherwise a name derived from the target's offset. Labeled targets
+        // are named first so offset names never steal a label. Labels that
+        // would not read back as the same name, such as ones that look like
+        // a name and an offset, are skipped.
+        let mut pointer_destinations: HashMap<usize, String> = HashMap::new();
+        let mut used: HashSet<String> = HashSet::new();
+        used.insert(NULL_POINTER.to_owned());
+        for ptr in &targets {
+            if *ptr >= archive.size() {
+                continue;
+            }
+            let labels = archive.read_labels(*ptr)?.unwrap_or_default();
+            if let Some(label) = labels
+                .into_iter()
+                .find(|label| !used.contains(label) && reads_as_name(label))
+            {
+                used.insert(label.clone());
+                pointer_destinations.insert(*ptr, label);
+            }
+        }
+        for ptr in &targets {
+            if !pointer_destinations.contains_key(ptr) {
+                let mut name = format!("ptr_0x{:X}", ptr);
+                while used.contains(&name) {
+                    name.push('_');
+                }
+                used.insert(name.clone());
+                pointer_destinations.insert(*ptr, name);
+            }
+        }
+
+        let mut entries: Vec<Entry> = Vec::new();
+        for addr in (0..archive.size()).step_by(4) {
+            for offset in 0..4 {
+                if let Some(name) = pointer_destinations.get(&(addr + offset)) {
+                    entries.push(Entry::PointerDest(name.clone(), offset));
+                }
+            }
+            if let Some(labels) = archive.read_labels(addr)? {
+                entries.extend(labels.into_iter().map(Entry::Label));
+            }
+            if pointers.get(&addr) == Some(&0) && zero_is_null {
+                entries.push(Entry::NullPointer);
+            } else if let Some(ptr) = pointers.get(&addr) {
+                entries.push(Entry::PointerSource(pointer_destinations[ptr].clone()));
+            } else if let Some(text) = archive.read_string(addr)? {
+                entries.push(Entry::Text(text));
+            } else {
+                let data = archive.read_bytes(addr, 4)?;
+                entries.push(Entry::Word([data[0], data[1], data[2], data[3]]));
+            }
+        }
+        if let Some(name) = pointer_destinations.get(&archive.size()) {
+            entries.push(Entry::PointerDest(name.clone(), 0));
+        }
+        if let Some(labels) = archive.read_labels(archive.size())? {
+            entries.extend(labels.into_iter().map(Entry::Label));
+        }
+        Ok(UnpackedArchive {
+            entries,
+            header: Header::default(),
+            comments: Vec::new(),
+        })
+    }
+
+    pub fn to_archive(&self) -> anyhow::Result<BinArchive> {
+        if let Some(index) = misplaced_destinations(&self.entries).first() {
+            if let Entry::PointerDest(pointer_id, offset) = &self.entries[*index] {
+                return Err(anyhow::anyhow!(
+                    "Destination {} +{} is outside the entry it marks",
+                    pointer_id,
+                    offset
+                ));
+            }
+        }
+        let mut pointers: HashMap<&str, usize> = HashMap::new();
+        let mut pointer_sources: Vec<(usize, &str)> = Vec::new();
+        let mut null_pointers: Vec<usize> = Vec::new();
+        let mut archive = BinArchive::new();
+        archive.allocate_at_end(self.size());
+        let mut writer = BinArchiveWriter::new(&mut archive, 0);
+        for entry in &self.entries {
+            match entry {
+                Entry::Word(data) | Entry::Typed(_, data) => writer.write_bytes(data)?,
+                Entry::Field(_, FieldValue::Numbers(_, bytes)) => writer.write_bytes(bytes)?,
+                Entry::Text(text) | Entry::Field(_, FieldValue::String(text)) => {
+                    writer.write_string(Some(text))?
+                }
+                Entry::Field(_, FieldValue::Pointer(pointer_id)) if pointer_id != NULL_POINTER => {
+                    pointer_sources.push((writer.tell(), pointer_id));
+                    writer.write_u32(0)?;
+                }
+                Entry::PointerSource(pointer_id) => {
+                    pointer_sources.push((writer.tell(), pointer_id));
+                    writer.write_u32(0)?;
+                }
+                Entry::NullPointer | Entry::Field(_, FieldValue::Pointer(_)) => {
+                    null_pointers.push(writer.tell());
+                    writer.write_u32(0)?;
+                }
+                Entry::PointerDest(pointer_id, offset) => {
+                    if pointer_id == NULL_POINTER {
+                        return Err(anyhow::anyhow!(
+                            "Destination name {} is reserved",
+                            pointer_id
+                        ));
+                    }
+                    if pointers
+                        .insert(pointer_id, writer.tell() + offset)
+                        .is_some()
+                    {
+                        return Err(anyhow::anyhow!("Duplicate destination {}", pointer_id));
+                    }
+                }
+                Entry::Label(label) => {
+                    log::debug!("Label {} at 0x{:X}", label, writer.tell());
+                    writer.write_label(label)?;
+                }
+            }
+        }
+        for (addr, pointer_id) in pointer_sources {
+            if let Some(dest) = pointers.get(pointer_id) {
+                log::debug!("Pointer 0x{:X} -> 0x{:X} ({})", addr, dest, pointer_id);
+                archive.write_pointer(addr, Some(*dest))?;
+            } else {
+                return Err(anyhow::anyhow!("Unresolved pointer {}", pointer_id));
+            }
+        }
+        for addr in null_pointers {
+            log::debug!("Pointer 0x{:X} -> null", addr);
+            archive.write_pointer(addr, Some(0))?;
+        }
+        log::debug!("Packed archive data is {} bytes", archive.size());
+        Ok(archive)
+    }
+}
@@ -0,0 +1,636 @@
+use crate::align::{sections, Alignment, Section, SectionKey, Slot, Target};
+use crate::diff::compare;
+use crate::model::{Entry, UnpackedArchive};
+use crate::sha1::sha1_hex;
+use crate::structured::is_zero;
+use crate::unpacker::parse_word;
+use mila::BinArchive;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::Range;
+
+const PATCH_VERSION: u32 = 3;
+
+// Slots on each side of a run that are checked when finding it.
+const CONTEXT: usize = 2;
+
+/// The changes between two versions of an archive.
+///
+/// A patch holds only the runs of slots that changed. Everything it leaves
+/// alone is represented by digests, which are used to find each run in the
+/// archive being patched and to check that the run is still what the patch
+/// replaces. A copy of the original archive with other changes, such as
+/// words inserted into the same table, can still be patched.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Patch {
+    version: u32,
+    #[serde(default)]
+    count: usize,
+    #[serde(default)]
+    hunks: Vec<Hunk>,
+    #[serde(default)]
+    sections: Vec<NewSection>,
+}
+
+impl Patch {
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+/// A run of slots in a section of the original archive, as digests of the
+/// run and of up to `CONTEXT` slots on each side. Less context on a side
+/// means the run is that close to the edge of the section.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Anchor {
+    location: String,
+    #[serde(flatten)]
+    section: SectionKey,
+    position: usize,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    before: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    slots: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    after: Vec<String>,
+}
+
+/// A run of slots the patch replaces, and the slots that replace it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Hunk {
+    #[serde(flatten)]
+    anchor: Anchor,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    added: Vec<PatchSlot>,
+}
+
+/// A section only the edited archive has. It is placed after `follows`, or
+/// first if that is `None`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct NewSection {
+    #[serde(flatten)]
+    key: SectionKey,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    follows: Option<SectionKey>,
+    slots: Vec<PatchSlot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "lowercase")]
+enum PatchSlot {
+    Label(String),
+    Pointer(Reference),
+    Null,
+    String(String),
+    Raw(String),
+}
+
+/// The word a pointer added by the patch leads to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Reference {
+    /// A word the patch adds, by its position in the edited section.
+    Added {
+        section: SectionKey,
+        position: usize,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        offset: usize,
+    },
+    /// A word the patch leaves alone, found the same way as a run.
+    Original {
+        anchor: Anchor,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        offset: usize,
+    },
+    End,
+}
+
+// Pointers are identified by the section they lead into, since the
+// position of their target can differ between copies.
+fn digest(slot: &Slot) -> String {
+    let text = match slot {
+        Slot::Label(name) => format!("label {}", name),
+        Slot::Pointer(Target::Word {
+            section, offset, ..
+        }) => format!("pointer {:?} {} {}", section.label, section.index, offset),
+        Slot::Pointer(Target::End) => "pointer end".to_owned(),
+        Slot::Null => "null".to_owned(),
+        Slot::String(text) => format!("string {}", text),
+        Slot::Raw(data) => format!("raw {}", data),
+    };
+    sha1_hex(text.as_bytes())[..8].to_owned()
+}
+
+fn digests(section: &Section) -> Vec<String> {
+    section.slots.iter().map(digest).collect()
+}
+
+impl Anchor {
+    fn new(section: &Section, digests: &[String], run: Range<usize>) -> Self {
+        Anchor {
+            location: section.location(run.start),
+            section: section.key.clone(),
+            position: run.start,
+            before: digests[run.start.saturating_sub(CONTEXT)..run.start].to_vec(),
+            slots: digests[run.clone()].to_vec(),
+            after: digests[run.end..(run.end + CONTEXT).min(digests.len())].to_vec(),
+        }
+    }
+
+    // Returns where the run starts in a section with the given digests,
+    // taking the match closest to `expected` if there are several.
+    fn find(&self, digests: &[String], expected: usize) -> Option<usize> {
+        let pattern: Vec<&String> = self
+            .before
+            .iter()
+            .chain(&self.slots)
+            .chain(&self.after)
+            .collect();
+        let last = digests.len().checked_sub(pattern.len())?;
+        (0..=last)
+            .filter(|&start| self.before.len() >= CONTEXT || start == 0)
+            .filter(|&start| self.after.len() >= CONTEXT || start == last)
+            .filter(|&start| digests[start..].iter().zip(&pattern).all(|(a, b)| a == *b))
+            .map(|start| start + self.before.len())
+            .min_by_key(|&position| (position as isize - expected as isize).abs())
+    }
+
+    fn mismatch(&self) -> anyhow::Error {
+        anyhow::anyhow!("Archive does not match the patch at {}", self.location)
+    }
+}
+
+// A run of slots in a section of the original archive and what replaces it
+// in the edited one, if the edited archive still has the section.
+type Run<'a> = (&'a Section, Range<usize>, Option<&'a Section>, Range<usize>);
+
+// Returns the runs of slots that differ between two versions of a section,
+// as ranges of positions in each.
+fn runs(alignment: &Alignment, old: &Section, new: &Section) -> Vec<(Range<usize>, Range<usize>)> {
+    let mut runs = Vec::new();
+    let mut start: Option<(usize, usize)> = None;
+    let (mut i, mut j) = (0, 0);
+    for pair in alignment.pairs(&old.key) {
+        let same = match *pair {
+            (Some(a), Some(b)) => alignment.same(&old.slots[a], &new.slots[b]),
+            _ => false,
+        };
+        if same {
+            if let Some((si, sj)) = start.take() {
+                runs.push((si..i, sj..j));
+            }
+        } else if start.is_none() {
+            start = Some((i, j));
+        }
+        i += pair.0.is_some() as usize;
+        j += pair.1.is_some() as usize;
+    }
+    if let Some((si, sj)) = start {
+        runs.push((si..i, sj..j));
+    }
+    runs
+}
+
+pub fn make_patch(original: &BinArchive, edited: &BinArchive) -> anyhow::Result<Patch> {
+    let original = sections(&UnpackedArchive::from_archive(original)?)?;
+    let edited = sections(&UnpackedArchive::from_archive(edited)?)?;
+    let alignment = Alignment::new(&original, &edited);
+    let original_sections: HashMap<&SectionKey, &Section> = original
+        .iter()
+        .map(|section| (&section.key, section))
+        .collect();
+    let edited_sections: HashMap<&SectionKey, &Section> = edited
+        .iter()
+        .map(|section| (&section.key, section))
+        .collect();
+    let original_digests: HashMap<&SectionKey, Vec<String>> = original
+        .iter()
+        .map(|section| (&section.key, digests(section)))
+        .collect();
+
+    // A section the edited archive no longer has is one run removing all
+    // of it.
+    let mut changed: Vec<Run> = Vec::new();
+    for section in &original {
+        match edited_sections.get(&section.key) {
+            Some(other) => changed.extend(
+                runs(&alignment, section, other)
+                    .into_iter()
+                    .map(|(old, new)| (section, old, Some(*other), new)),
+            ),
+            None => changed.push((section, 0..section.slots.len(), None, 0..0)),
+        }
+    }
+
+    // Pointers to words the patch adds refer to them by position. Words it
+    // leaves alone are found by their context instead.
+    let added = |section: &SectionKey, position: usize| {
+        changed.iter().any(|(_, _, other, new)| {
+            matches!(other, Some(other) if &other.key == section) && new.contains(&position)
+        })
+    };
+    let reference = |target: &Target| -> Reference {
+        let (section, position, offset) = match target {
+            Target::Word {
+                section,
+                position,
+                offset,
+            } => (section, *position, *offset),
+            Target::End => return Reference::End,
+        };
+        match alignment
+            .backward(section, position)
+            .filter(|_| !added(section, position))
+        {
+            Some(original_position) => Reference::Original {
+                anchor: Anchor::new(
+                    original_sections[section],
+                    &original_digests[section],
+                    original_position..original_position + 1,
+                ),
+                offset,
+            },
+            None => Reference::Added {
+                section: section.clone(),
+                position,
+                offset,
+            },
+        }
+    };
+    let convert = |slots: &[Slot]| -> Vec<PatchSlot> {
+        slots
+            .iter()
+            .map(|slot| match slot {
+                Slot::Label(name) => PatchSlot::Label(name.clone()),
+                Slot::Pointer(target) => PatchSlot::Pointer(reference(target)),
+                Slot::Null => PatchSlot::Null,
+                Slot::String(text) => PatchSlot::String(text.clone()),
+                Slot::Raw(data) => PatchSlot::Raw(data.clone()),
+            })
+            .collect()
+    };
+
+    let hunks = changed
+        .iter()
+        .map(|(section, old, other, new)| Hunk {
+            anchor: Anchor::new(section, &original_digests[&section.key], old.clone()),
+            added: other.map_or_else(Vec::new, |other| convert(&other.slots[new.clone()])),
+        })
+        .collect();
+    let mut new_sections = Vec::new();
+    let mut follows: Option<&SectionKey> = None;
+    for section in &edited {
+        if !original_sections.contains_key(&section.key) {
+            new_sections.push(NewSection {
+                key: section.key.clone(),
+                follows: follows.cloned(),
+                slots: convert(&section.slots),
+            });
+        }
+        follows = Some(&section.key);
+    }
+    Ok(Patch {
+        version: PATCH_VERSION,
+        count: compare(&original, &edited).len(),
+        hunks,
+        sections: new_sections,
+    })
+}
+
+enum Source<'a> {
+    Ours(&'a Section, usize),
+    Patch(&'a PatchSlot),
+}
+
+impl Source<'_> {
+    fn is_label(&self) -> bool {
+        match self {
+            Source::Ours(section, position) => matches!(section.slots[*position], Slot::Label(_)),
+            Source::Patch(slot) => matches!(slot, PatchSlot::Label(_)),
+        }
+    }
+}
+
+/// Applies a patch made by `make_patch` to a copy of the original archive.
+///
+/// Fails without applying anything if the archive changed any of the words
+/// the patch replaces, or the words around them.
+pub fn apply_patch(original: &BinArchive, patch: &Patch) -> anyhow::Result<BinArchive> {
+    if patch.version != PATCH_VERSION {
+        return Err(anyhow::anyhow!(
+            "Patch version {} is not supported (expected {})",
+            patch.version,
+            PATCH_VERSION
+        ));
+    }
+    let ours = sections(&UnpackedArchive::from_archive(original)?)?;
+    let index: HashMap<&SectionKey, usize> = ours
+        .iter()
+        .enumerate()
+        .map(|(index, section)| (&section.key, index))
+        .collect();
+    let our_digests: Vec<Vec<String>> = ours.iter().map(digests).collect();
+
+    // Find every run before changing anything. Runs in the same section
+    // are expected to have moved by the same amount.
+    let mut found: Vec<Vec<(Range<usize>, usize, &Hunk)>> = vec![Vec::new(); ours.len()];
+    let mut shifts: HashMap<usize, isize> = HashMap::new();
+    let mut edited_positions: HashMap<&SectionKey, isize> = HashMap::new();
+    for hunk in &patch.hunks {
+        let anchor = &hunk.anchor;
+        let section = *index
+            .get(&anchor.section)
+            .ok_or_else(|| anchor.mismatch())?;
+        let shift = shifts.entry(section).or_insert(0);
+        let expected = (anchor.position as isize + *shift).max(0) as usize;
+        let start = anchor
+            .find(&our_digests[section], expected)
+            .ok_or_else(|| anchor.mismatch())?;
+        *shift = start as isize - anchor.position as isize;
+
+        // Where the added slots start in the edited section.
+        let delta = edited_positions.entry(&anchor.section).or_insert(0);
+        let edited_start = (anchor.position as isize + *delta) as usize;
+        *delta += hunk.added.len() as isize - anchor.slots.len() as isize;
+        found[section].push((start..start + anchor.slots.len(), edited_start, hunk));
+    }
+
+    let mut nodes: Vec<Source> = Vec::new();
+    let mut our_nodes: HashMap<(usize, usize), usize> = HashMap::new();
+    let mut added_nodes: HashMap<(&SectionKey, usize), usize> = HashMap::new();
+    let mut layout: Vec<(&SectionKey, Vec<usize>)> = Vec::new();
+    for (section_index, section) in ours.iter().enumerate() {
+        let runs = &mut found[section_index];
+        runs.sort_by_key(|(run, _, _)| run.start);
+        let mut slots = Vec::new();
+        let mut next = 0;
+        for (run, edited_start, hunk) in runs.iter() {
+            if run.start < next {
+                return Err(hunk.anchor.mismatch());
+            }
+            for position in next..run.start {
+                our_nodes.insert((section_index, position), nodes.len());
+                slots.push(nodes.len());
+                nodes.push(Source::Ours(section, position));
+            }
+            let first = nodes.len();
+            for (offset, slot) in hunk.added.iter().enumerate() {
+                added_nodes.insert((&hunk.anchor.section, edited_start + offset), nodes.len());
+                slots.push(nodes.len());
+                nodes.push(Source::Patch(slot));
+            }
+            // Our pointers to replaced words lead to their replacements.
+            for (offset, position) in run.clone().enumerate() {
+                if offset < hunk.added.len()
+                    && !nodes[first + offset].is_label()
+                    && !matches!(section.slots[position], Slot::Label(_))
+                {
+                    our_nodes.insert((section_index, position), first + offset);
+                }
+            }
+            next = run.end;
+        }
+        for position in next..section.slots.len() {
+            our_nodes.insert((section_index, position), nodes.len());
+            slots.push(nodes.len());
+            nodes.push(Source::Ours(section, position));
+        }
+        layout.push((&section.key, slots));
+    }
+
+    let mut first = 0;
+    for section in &patch.sections {
+        if layout.iter().any(|(key, _)| *key == &section.key) {
+            return Err(anyhow::anyhow!(
+                "Archive already has section {}, which the patch adds",
+                section.key
+            ));
+        }
+        let mut slots = Vec::new();
+        for (position, slot) in section.slots.iter().enumerate() {
+            added_nodes.insert((&section.key, position), nodes.len());
+            slots.push(nodes.len());
+            nodes.push(Source::Patch(slot));
+        }
+        let at = match &section.follows {
+            Some(follows) => {
+                layout
+                    .iter()
+                    .position(|(key, _)| *key == follows)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Archive does not match the patch at {}", follows)
+                    })?
+                    + 1
+            }
+            None => {
+                first += 1;
+                first - 1
+            }
+        };
+        layout.insert(at, (&section.key, slots));
+    }
+
+    // The node and offset each pointer leads to, or `None` for the end of
+    // the data.
+    let mut destinations: HashMap<usize, Option<(usize, usize)>> = HashMap::new();
+    for (node, source) in nodes.iter().enumerate() {
+        let destination = match source {
+            Source::Ours(section, position) => match &section.slots[*position] {
+                Slot::Pointer(Target::Word {
+                    section: target,
+                    position: target_position,
+                    offset,
+                }) => {
+                    let target = our_nodes
+                        .get(&(index[target], *target_position))
+                        .ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "Pointer at {} leads to a word the patch removes",
+                                section.location(*position)
+                            )
+                        })?;
+                    Some((*target, *offset))
+                }
+                Slot::Pointer(Target::End) => None,
+                _ => continue,
+            },
+            Source::Patch(PatchSlot::Pointer(reference)) => match reference {
+                Reference::Added {
+                    section,
+                    position,
+                    offset,
+                } => {
+                    let target = added_nodes.get(&(section, *position)).ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "Patch refers to {}[{}], which it does not add",
+                            section,
+                            position
+                        )
+                    })?;
+                    Some((*target, *offset))
+                }
+                Reference::Original { anchor, offset } => {
+                    let section = *index
+                        .get(&anchor.section)
+                        .ok_or_else(|| anchor.mismatch())?;
+                    let target = anchor
+                        .find(&our_digests[section], anchor.position)
+                        .and_then(|position| our_nodes.get(&(section, position)))
+                        .ok_or_else(|| anchor.mismatch())?;
+                    Some((*target, *offset))
+                }
+                Reference::End => None,
+            },
+            Source::Patch(_) => continue,
+        };
+        destinations.insert(node, destination);
+    }
+    let mut names: HashMap<Option<(usize, usize)>, String> = HashMap::new();
+    for (_, slots) in &layout {
+        for node in slots {
+            if let Some(destination) = destinations.get(node) {
+                let count = names.len();
+                names
+                    .entry(*destination)
+                    .or_insert_with(|| (count + 1).to_string());
+            }
+        }
+    }
+
+    let mut entries = Vec::new();
+    for (_, slots) in &layout {
+        for &node in slots {
+            let source = &nodes[node];
+            if !source.is_label() {
+                for offset in 0..4 {
+                    if let Some(name) = names.get(&Some((node, offset))) {
+                        entries.push(Entry::PointerDest(name.clone(), offset));
+                    }
+                }
+            }
+            let pointer = || Entry::PointerSource(names[&destinations[&node]].clone());
+            entries.push(match source {
+                Source::Ours(section, position) => match &section.slots[*position] {
+                    Slot::Label(name) => Entry::Label(name.clone()),
+                    Slot::Pointer(_) => pointer(),
+                    Slot::Null => Entry::NullPointer,
+                    Slot::String(text) => Entry::Text(text.clone()),
+                    Slot::Raw(data) => Entry::Word(parse_word(data)?),
+                },
+                Source::Patch(slot) => match slot {
+                    PatchSlot::Label(name) => Entry::Label(name.clone()),
+                    PatchSlot::Pointer(_) => pointer(),
+                    PatchSlot::Null => Entry::NullPointer,
+                    PatchSlot::String(text) => Entry::Text(text.clone()),
+                    PatchSlot::Raw(data) => Entry::Word(parse_word(data)?),
+                },
+            });
+        }
+    }
+    if let Some(name) = names.get(&None) {
+        entries.push(Entry::PointerDest(name.clone(), 0));
+    }
+    UnpackedArchive {
+        entries,
+        ..UnpackedArchive::default()
+    }
+    .to_archive()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::Header;
+    use crate::unpacker::{pack, unpack};
+
+    const ORIGINAL: &str = "LABEL: A\n0x00000001\n0x00000002\n0x00000003";
+
+    fn patch(edited: &str) -> Patch {
+        make_patch(&pack(ORIGINAL).unwrap(), &pack(edited).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn applies_to_a_copy_with_a_different_length() {
+        let patch = patch("LABEL: A\n0x00000001\n0x00000002\n0x00000009");
+        assert_eq!(patch.len(), 1);
+        let copy = pack("LABEL: A\n0x00000007\n0x00000001\n0x00000002\n0x00000003").unwrap();
+        let patched = apply_patch(&copy, &patch).unwrap();
+        let text = unpack(&patched).unwrap();
+        assert_eq!(
+            text.lines()
+                .skip(Header::default().lines().len())
+                .collect::<Vec<_>>(),
+            vec![
+                "LABEL: A",
+                "0x00000007",
+                "0x00000001",
+                "0x00000002",
+                "0x00000009"
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_a_copy_that_changed_the_same_word() {
+        let patch = patch("LABEL: A\n0x00000001\n0x00000002\n0x00000009");
+        let copy = pack("LABEL: A\n0x00000001\n0x00000002\n0x00000005").unwrap();
+        let error = apply_patch(&copy, &patch).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Archive does not match the patch at A+0x8"
+        );
+    }
+
+    const TABLES: &str = "\
+LABEL: TOP
+SRC: a
+SRC: b
+0x00000001
+0x00000002
+0x00000003
+LABEL: A
+DEST: a
+Secret line one
+LABEL: B
+DEST: b
+Secret line two";
+
+    #[test]
+    fn unchanged_sections_are_not_in_the_patch() {
+        let original = pack(TABLES).unwrap();
+        let edited = pack(&TABLES.replace("0x00000003", "0x00000009")).unwrap();
+        let patch = make_patch(&original, &edited).unwrap();
+        let json = serde_json::to_string(&patch).unwrap();
+        assert!(json.contains("0x00000009"));
+        assert!(!json.contains("Secret line"));
+        assert!(!json.contains("0x00000001"));
+        assert_eq!(
+            apply_patch(&original, &patch).unwrap().serialize().unwrap(),
+            edited.serialize().unwrap()
+        );
+    }
+
+    #[test]
+    fn added_pointers_refer_to_words_by_position() {
+        let original = pack(TABLES).unwrap();
+        let edited = pack(&format!(
+            "{}\nSRC: d\nLABEL: C\nDEST: d\nNew line",
+            TABLES
+                .replace("SRC: b", "SRC: b\nSRC: c")
+                .replace("DEST: b", "DEST: b\nDEST: c")
+        ))
+        .unwrap();
+        let patch = make_patch(&original, &edited).unwrap();
+        let json = serde_json::to_string(&patch).unwrap();
+        assert!(json.contains("New line"));
+        assert!(!json.contains("Secret line"));
+        assert_eq!(
+            apply_patch(&original, &patch).unwrap().serialize().unwrap(),
+            edited.serialize().unwrap()
+        );
+    }
+}
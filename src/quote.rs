@@ -0,0 +1,148 @@
+use crate::error::PackErrorKind;
+use std::ops::Range;
+
+/// Writes `text` as a double-quoted string.
+///
+/// Quotes, backslashes and control characters are escaped as `\"`, `\\`,
+/// `\n`, `\r`, `\t`, `\0`, `\xNN` (ASCII) or `\u{N}`. Everything else is
+/// written as is.
+///
+/// Only UTF-8 text can be quoted. Strings are decoded when an archive is
+/// read, so bytes that are not valid UTF-8 never reach the text format, and
+/// `\xNN` stands for a character rather than a raw byte.
+pub(crate) fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\0' => quoted.push_str("\\0"),
+            c if c.is_ascii_control() => quoted.push_str(&format!("\\x{:02X}", c as u32)),
+            c if c.is_control() => quoted.push_str(&format!("\\u{{{:X}}}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Decodes the escape sequence at the start of `rest`, which follows a
+// backslash, returning the character and the length of the sequence.
+fn escape(rest: &str) -> Option<(char, usize)> {
+    let c = match rest.chars().next()? {
+        '"' => '"',
+        '\\' => '\\',
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        '0' => '\0',
+        'x' => {
+            let digits = rest.get(1..3)?;
+            if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            let value = u8::from_str_radix(digits, 16).ok()?;
+            return if value.is_ascii() {
+                Some((value as char, 3))
+            } else {
+                None
+            };
+        }
+        'u' => {
+            let end = rest.find('}')?;
+            let digits = rest[..end].strip_prefix("u{")?;
+            if digits.is_empty()
+                || digits.len() > 6
+                || !digits.chars().all(|c| c.is_ascii_hexdigit())
+            {
+                return None;
+            }
+            let value = char::from_u32(u32::from_str_radix(digits, 16).ok()?)?;
+            return Some((value, end + 1));
+        }
+        _ => return None,
+    };
+    Some((c, 1))
+}
+
+/// Reads a double-quoted string from the start of `text`, returning it and
+/// the number of bytes it took up.
+///
+/// Errors carry the span within `text` that they point at.
+pub(crate) fn unquote(text: &str) -> Result<(String, usize), (Range<usize>, PackErrorKind)> {
+    let mut unquoted = String::new();
+    let mut position = 1;
+    while let Some(c) = text[position..].chars().next() {
+        match c {
+            '"' => return Ok((unquoted, position + 1)),
+            '\\' => match escape(&text[position + 1..]) {
+                Some((c, length)) => {
+                    unquoted.push(c);
+                    position += 1 + length;
+                }
+                None => {
+                    let end = text[position + 1..]
+                        .chars()
+                        .next()
+                        .map_or(text.len(), |c| position + 1 + c.len_utf8());
+                    return Err((position..end, PackErrorKind::BadEscape));
+                }
+            },
+            c => {
+                unquoted.push(c);
+                position += c.len_utf8();
+            }
+        }
+    }
+    Err((0..text.len(), PackErrorKind::UnterminatedString))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        for text in [
+            "",
+            "plain",
+            "say \"hi\"",
+            "a\\b",
+            "tab\there\r\n",
+            "\0\x07\x7F",
+            "\u{85}é",
+        ]
+        .iter()
+        {
+            let quoted = quote(text);
+            assert_eq!(unquote(&quoted), Ok(((*text).to_owned(), quoted.len())));
+        }
+    }
+
+    #[test]
+    fn escapes_control_characters() {
+        assert_eq!(quote("a\x01\u{85}"), "\"a\\x01\\u{85}\"");
+    }
+
+    #[test]
+    fn stops_at_the_closing_quote() {
+        assert_eq!(unquote("\"a\" rest"), Ok(("a".to_owned(), 3)));
+    }
+
+    #[test]
+    fn rejects_bad_strings() {
+        assert_eq!(
+            unquote("\"abc").unwrap_err().1,
+            PackErrorKind::UnterminatedString
+        );
+        assert_eq!(unquote("\"\\q\"").unwrap_err().1, PackErrorKind::BadEscape);
+        assert_eq!(
+            unquote("\"\\xFF\"").unwrap_err().1,
+            PackErrorKind::BadEscape
+        );
+    }
+}
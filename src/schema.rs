@@ -0,0 +1,622 @@
+//! Schemas describing the layout of structs in an archive.
+//!
+//! A schema is a YAML file naming struct layouts and the labels they are
+//! found at:
+//!
+//! ```yaml
+//! structs:
+//!   Character:
+//!     - { name: pid, type: string }
+//!     - { name: level, type: u8 }
+//!     - { name: stats, type: u8, count: 7 }
+//!     - { name: class, type: pointer }
+//! labels:
+//!   CHARACTERS: Character
+//! ```
+//!
+//! Field types are `u8`, `i8`, `u16`, `i16`, `u32`, `i32`, `f32`, `string`
+//! and `pointer`. `count` makes a field an array. Strings and pointers take a
+//! whole word, so they must sit at a multiple of 4 bytes into the struct, and
+//! every struct must be a whole number of words long.
+//!
+//! Unpacking applies the struct at each listed label as many times as the
+//! data allows, stopping at the next label. A label can also be given one of
+//! the numeric types instead of a struct, which displays every raw word up to
+//! the next label as typed words, e.g. `ITEM_PRICES: u16`.
+
+use crate::model::{Entry, FieldValue, UnpackedArchive, NULL_POINTER};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+/// The byte order of numbers in an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+impl Endian {
+    pub fn name(self) -> &'static str {
+        match self {
+            Endian::Little => "little",
+            Endian::Big => "big",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "little" => Some(Endian::Little),
+            "big" => Some(Endian::Big),
+            _ => None,
+        }
+    }
+
+    // Converts a value's bytes between this byte order and little-endian.
+    fn to_little(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Endian::Little => bytes.to_vec(),
+            Endian::Big => bytes.iter().rev().copied().collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScalarType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl ScalarType {
+    pub fn size(self) -> usize {
+        match self {
+            ScalarType::U8 | ScalarType::I8 => 1,
+            ScalarType::U16 | ScalarType::I16 => 2,
+            ScalarType::U32 | ScalarType::I32 | ScalarType::F32 => 4,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ScalarType::U8 => "u8",
+            ScalarType::I8 => "i8",
+            ScalarType::U16 => "u16",
+            ScalarType::I16 => "i16",
+            ScalarType::U32 => "u32",
+            ScalarType::I32 => "i32",
+            ScalarType::F32 => "f32",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "u8" => Some(ScalarType::U8),
+            "i8" => Some(ScalarType::I8),
+            "u16" => Some(ScalarType::U16),
+            "i16" => Some(ScalarType::I16),
+            "u32" => Some(ScalarType::U32),
+            "i32" => Some(ScalarType::I32),
+            "f32" => Some(ScalarType::F32),
+            _ => None,
+        }
+    }
+
+    /// Formats a single value of this type.
+    ///
+    /// NaNs are written as hex bits, since their payload would not survive
+    /// a decimal round trip.
+    pub(crate) fn format(self, bytes: &[u8], endian: Endian) -> String {
+        let mut word = [0; 4];
+        word[..bytes.len()].copy_from_slice(&endian.to_little(bytes));
+        let half = [word[0], word[1]];
+        match self {
+            ScalarType::U8 => word[0].to_string(),
+            ScalarType::I8 => (word[0] as i8).to_string(),
+            ScalarType::U16 => u16::from_le_bytes(half).to_string(),
+            ScalarType::I16 => i16::from_le_bytes(half).to_string(),
+            ScalarType::U32 => u32::from_le_bytes(word).to_string(),
+            ScalarType::I32 => i32::from_le_bytes(word).to_string(),
+            ScalarType::F32 => {
+                let value = f32::from_le_bytes(word);
+                if value.is_nan() {
+                    format!("0x{:08X}", value.to_bits())
+                } else {
+                    value.to_string()
+                }
+            }
+        }
+    }
+
+    /// Parses a single value of this type into bytes.
+    ///
+    /// Besides decimal, any type accepts `0x` followed by its bits in hex.
+    pub(crate) fn parse(self, text: &str, endian: Endian) -> Option<Vec<u8>> {
+        // Little and big endian are mirror images, so converting the
+        // little-endian encoding works in both directions.
+        let bytes = self.parse_little(text)?;
+        Some(endian.to_little(&bytes))
+    }
+
+    fn parse_little(self, text: &str) -> Option<Vec<u8>> {
+        if let Some(hex) = text.strip_prefix("0x") {
+            let bits = u32::from_str_radix(hex, 16).ok()?;
+            if self.size() < 4 && bits >> (self.size() * 8) != 0 {
+                return None;
+            }
+            return Some(bits.to_le_bytes()[..self.size()].to_vec());
+        }
+        let bytes = match self {
+            ScalarType::U8 => text.parse::<u8>().ok()?.to_le_bytes().to_vec(),
+            ScalarType::I8 => text.parse::<i8>().ok()?.to_le_bytes().to_vec(),
+            ScalarType::U16 => text.parse::<u16>().ok()?.to_le_bytes().to_vec(),
+            ScalarType::I16 => text.parse::<i16>().ok()?.to_le_bytes().to_vec(),
+            ScalarType::U32 => text.parse::<u32>().ok()?.to_le_bytes().to_vec(),
+            ScalarType::I32 => text.parse::<i32>().ok()?.to_le_bytes().to_vec(),
+            ScalarType::F32 => text.parse::<f32>().ok()?.to_le_bytes().to_vec(),
+        };
+        Some(bytes)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) enum FieldType {
+    Scalar(ScalarType),
+    String,
+    Pointer,
+}
+
+impl FieldType {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "string" => Some(FieldType::String),
+            "pointer" => Some(FieldType::Pointer),
+            name => ScalarType::from_name(name).map(FieldType::Scalar),
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            FieldType::Scalar(scalar) => scalar.size(),
+            FieldType::String | FieldType::Pointer => 4,
+        }
+    }
+}
+
+impl TryFrom<String> for FieldType {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        FieldType::from_name(&name).ok_or_else(|| format!("unknown field type '{}'", name))
+    }
+}
+
+fn default_count() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FieldDef {
+    name: String,
+
+    #[serde(rename = "type")]
+    kind: FieldType,
+
+    #[serde(default = "default_count")]
+    count: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schema {
+    #[serde(default)]
+    structs: HashMap<String, Vec<FieldDef>>,
+
+    #[serde(default)]
+    labels: HashMap<String, String>,
+}
+
+impl Schema {
+    pub fn from_yaml(text: &str) -> anyhow::Result<Self> {
+        let schema: Schema = serde_yaml::from_str(text)?;
+        for (name, fields) in &schema.structs {
+            let mut offset = 0;
+            for field in fields {
+                if field.name.is_empty()
+                    || field.name.contains(|c: char| c == ':' || c.is_whitespace())
+                {
+                    return Err(anyhow::anyhow!(
+                        "Struct {} has an invalid field name '{}'",
+                        name,
+                        field.name
+                    ));
+                }
+                if field.count == 0 {
+                    return Err(anyhow::anyhow!(
+                        "Field {}.{} has a count of 0",
+                        name,
+                        field.name
+                    ));
+                }
+                let whole_word = matches!(field.kind, FieldType::String | FieldType::Pointer);
+                if whole_word && offset % 4 != 0 {
+                    return Err(anyhow::anyhow!(
+                        "Field {}.{} is at offset {}, which is not a multiple of 4",
+                        name,
+                        field.name,
+                        offset
+                    ));
+                }
+                offset += field.kind.size() * field.count;
+            }
+            if offset == 0 || offset % 4 != 0 {
+                return Err(anyhow::anyhow!(
+                    "Struct {} is {} bytes long, which is not a whole number of words",
+                    name,
+                    offset
+                ));
+            }
+        }
+        for (label, name) in &schema.labels {
+            if !schema.structs.contains_key(name) && ScalarType::from_name(name).is_none() {
+                return Err(anyhow::anyhow!(
+                    "Label {} uses undefined struct {}",
+                    label,
+                    name
+                ));
+            }
+        }
+        Ok(schema)
+    }
+}
+
+// A word of data along with the destinations that point into it.
+struct Unit<'a> {
+    start: usize,
+    destinations: Vec<(&'a str, usize)>,
+    entry: &'a Entry,
+}
+
+fn collect_units(entries: &[Entry], start: usize) -> (Vec<Unit<'_>>, usize) {
+    let mut units = Vec::new();
+    let mut destinations = Vec::new();
+    let mut unit_start = start;
+    for (index, entry) in entries.iter().enumerate().skip(start) {
+        match entry {
+            Entry::Label(_) => return (units, unit_start),
+            Entry::PointerDest(name, offset) => destinations.push((name.as_str(), *offset)),
+            entry => {
+                units.push(Unit {
+                    start: unit_start,
+                    destinations: std::mem::take(&mut destinations),
+                    entry,
+                });
+                unit_start = index + 1;
+            }
+        }
+    }
+    (units, unit_start)
+}
+
+// Decodes one struct from the start of `units`, returning its entries and
+// the number of units it covers.
+fn decode_struct(units: &[Unit], fields: &[FieldDef]) -> Option<(Vec<Entry>, usize)> {
+    let mut decoded: Vec<(usize, Entry)> = Vec::new();
+    let mut position = 0;
+    for field in fields {
+        match field.kind {
+            FieldType::Scalar(scalar) => {
+                let length = scalar.size() * field.count;
+                let mut bytes = Vec::with_capacity(length);
+                for byte in position..position + length {
+                    match units.get(byte / 4)?.entry {
+                        Entry::Word(data) => bytes.push(data[byte % 4]),
+                        _ => return None,
+                    }
+                }
+                let value = FieldValue::Numbers(scalar, bytes);
+                decoded.push((position, Entry::Field(field.name.clone(), value)));
+                position += length;
+            }
+            kind => {
+                for index in 0..field.count {
+                    let value = match (kind, units.get(position / 4)?.entry) {
+                        (FieldType::String, Entry::Text(text)) => FieldValue::String(text.clone()),
+                        (FieldType::Pointer, Entry::PointerSource(pointer_id)) => {
+                            FieldValue::Pointer(pointer_id.clone())
+                        }
+                        (FieldType::Pointer, Entry::NullPointer) => {
+                            FieldValue::Pointer(NULL_POINTER.to_owned())
+                        }
+                        _ => return None,
+                    };
+                    let name = if field.count == 1 {
+                        field.name.clone()
+                    } else {
+                        format!("{}[{}]", field.name, index)
+                    };
+                    decoded.push((position, Entry::Field(name, value)));
+                    position += 4;
+                }
+            }
+        }
+    }
+
+    // Each destination goes before the field it points into.
+    let count = position / 4;
+    let mut destinations: Vec<(usize, &str)> = units[..count]
+        .iter()
+        .enumerate()
+        .flat_map(|(index, unit)| {
+            unit.destinations
+                .iter()
+                .map(move |(name, offset)| (index * 4 + offset, *name))
+        })
+        .collect();
+    destinations.sort_by_key(|(position, _)| *position);
+    let mut entries = Vec::new();
+    let mut pending = destinations.into_iter().peekable();
+    for (index, (start, field)) in decoded.iter().enumerate() {
+        let end = decoded.get(index + 1).map_or(position, |(next, _)| *next);
+        while let Some((target, name)) = pending.next_if(|(target, _)| *target < end) {
+            entries.push(Entry::PointerDest(name.to_owned(), target - start));
+        }
+        entries.push(field.clone());
+    }
+    Some((entries, count))
+}
+
+/// Guesses how a raw word is best displayed, if it looks like a number.
+///
+/// Small integers are preferred, then floats with a short decimal form,
+/// then bytes that all fit in 7 bits, then pairs of small 16-bit values.
+pub(crate) fn guess_type(word: [u8; 4], endian: Endian) -> Option<ScalarType> {
+    let little = endian.to_little(&word);
+    let value = u32::from_le_bytes([little[0], little[1], little[2], little[3]]);
+    let float = f32::from_bits(value);
+    let half = |bytes: &[u8]| {
+        let bytes = endian.to_little(bytes);
+        i16::from_le_bytes([bytes[0], bytes[1]])
+    };
+    let halves = [half(&word[..2]), half(&word[2..])];
+    if value < 0x10000 {
+        Some(ScalarType::U32)
+    } else if (-0x10000..0).contains(&(value as i32)) {
+        Some(ScalarType::I32)
+    } else if float.is_normal()
+        && (1e-3..=1e6).contains(&float.abs())
+        && float.to_string().len() <= 8
+    {
+        Some(ScalarType::F32)
+    } else if word.iter().all(|byte| *byte < 0x80) {
+        Some(ScalarType::U8)
+    } else if halves.iter().all(|half| (-0x1000..0x1000).contains(half)) {
+        if halves.iter().any(|half| *half < 0) {
+            Some(ScalarType::I16)
+        } else {
+            Some(ScalarType::U16)
+        }
+    } else {
+        None
+    }
+}
+
+impl UnpackedArchive {
+    /// Displays raw words that look like numbers as typed words.
+    pub fn type_words(&mut self) {
+        for entry in &mut self.entries {
+            if let Entry::Word(data) = entry {
+                if let Some(scalar) = guess_type(*data, self.header.endian) {
+                    *entry = Entry::Typed(scalar, *data);
+                }
+            }
+        }
+    }
+
+    /// Decodes the data at each label listed in the schema into fields, or
+    /// into typed words if the label is given a type instead of a struct.
+    pub fn apply_schema(&mut self, schema: &Schema) {
+        let entries = std::mem::take(&mut self.entries);
+        let mut index = 0;
+        while index < entries.len() {
+            let layout = match &entries[index] {
+                Entry::Label(label) => schema.labels.get(label),
+                _ => None,
+            };
+            self.entries.push(entries[index].clone());
+            index += 1;
+            let layout = match layout {
+                Some(layout) => layout,
+                None => continue,
+            };
+            while let Some(Entry::Label(_)) = entries.get(index) {
+                self.entries.push(entries[index].clone());
+                index += 1;
+            }
+            let fields = match (schema.structs.get(layout), ScalarType::from_name(layout)) {
+                (Some(fields), _) => fields,
+                (None, Some(scalar)) => {
+                    while let Some(entry) = entries.get(index) {
+                        self.entries.push(match entry {
+                            Entry::Label(_) => break,
+                            Entry::Word(data) => Entry::Typed(scalar, *data),
+                            entry => entry.clone(),
+                        });
+                        index += 1;
+                    }
+                    continue;
+                }
+                (None, None) => continue,
+            };
+
+            let (units, end) = collect_units(&entries, index);
+            let mut used = 0;
+            while let Some((decoded, count)) = decode_struct(&units[used..], fields) {
+                self.entries.extend(decoded);
+                used += count;
+            }
+            index = units.get(used).map_or(end, |unit| unit.start);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::Header;
+    use crate::unpacker::{pack, unpack_with_options, UnpackOptions};
+
+    const SCHEMA: &str = "\
+structs:
+  Character:
+    - { name: pid, type: string }
+    - { name: level, type: u8 }
+    - { name: stats, type: u8, count: 3 }
+    - { name: class, type: pointer }
+    - { name: hp, type: i16, count: 2 }
+labels:
+  CHARACTERS: Character
+  PRICES: u16
+";
+
+    const TEXT: &str = "\
+#! asset-pack v1
+SRC: stat
+SRC: CHARACTERS
+DEST: CHARACTERS
+LABEL: CHARACTERS
+Alfred
+DEST: stat +2
+0x05010203
+SRC: null
+0x1E00FFFF
+Clair
+0x0A040506
+SRC: PRICES
+0x01000200
+DEST: PRICES
+LABEL: PRICES
+0x14000A00
+0xFFFF0100";
+
+    fn unpack_as(endian: Endian) -> String {
+        let schema = Schema::from_yaml(SCHEMA).unwrap();
+        let options = UnpackOptions {
+            schema: Some(&schema),
+            typed_words: false,
+            header: Header {
+                endian,
+                ..Header::default()
+            },
+        };
+        unpack_with_options(&pack(TEXT).unwrap(), &options).unwrap()
+    }
+
+    fn body(text: &str) -> Vec<&str> {
+        text.lines().skip(Header::default().lines().len()).collect()
+    }
+
+    #[test]
+    fn decodes_structs_and_typed_labels() {
+        assert_eq!(
+            body(&unpack_as(Endian::Little)),
+            vec![
+                "SRC: ptr_0xE",
+                "SRC: CHARACTERS",
+                "DEST: CHARACTERS",
+                "LABEL: CHARACTERS",
+                ".pid: string Alfred",
+                ".level: u8 5",
+                "DEST: ptr_0xE +1",
+                ".stats: u8 1 2 3",
+                ".class: pointer null",
+                ".hp: i16 30 -1",
+                ".pid: string Clair",
+                ".level: u8 10",
+                ".stats: u8 4 5 6",
+                ".class: pointer PRICES",
+                ".hp: i16 1 2",
+                "DEST: PRICES",
+                "LABEL: PRICES",
+                "U16: 20, 10",
+                "U16: 65535, 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_numbers_in_the_header_byte_order() {
+        let text = unpack_as(Endian::Big);
+        let lines = body(&text);
+        assert!(lines.contains(&".hp: i16 7680 -1"));
+        assert!(lines.contains(&"U16: 5120, 2560"));
+    }
+
+    #[test]
+    fn packs_decoded_structs_to_the_same_bytes() {
+        let original = pack(TEXT).unwrap().serialize().unwrap();
+        for endian in [Endian::Little, Endian::Big].iter() {
+            let text = unpack_as(*endian);
+            assert_eq!(pack(&text).unwrap().serialize().unwrap(), original);
+        }
+    }
+
+    #[test]
+    fn leaves_data_that_does_not_fit_the_struct() {
+        let schema = Schema::from_yaml(SCHEMA).unwrap();
+        let text = "#! asset-pack v1\n0x00000000\nLABEL: CHARACTERS\nAlfred\n0x05010203\nSRC: null";
+        let options = UnpackOptions {
+            schema: Some(&schema),
+            ..UnpackOptions::default()
+        };
+        let unpacked = unpack_with_options(&pack(text).unwrap(), &options).unwrap();
+        assert_eq!(
+            body(&unpacked),
+            vec![
+                "0x00000000",
+                "LABEL: CHARACTERS",
+                "Alfred",
+                "0x05010203",
+                "SRC: null"
+            ]
+        );
+    }
+
+    fn schema_error(text: &str) -> String {
+        Schema::from_yaml(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn rejects_invalid_schemas() {
+        assert_eq!(
+            schema_error(
+                "structs:\n  S:\n    - { name: a, type: u8 }\n    - { name: b, type: string }"
+            ),
+            "Field S.b is at offset 1, which is not a multiple of 4"
+        );
+        assert_eq!(
+            schema_error("structs:\n  S:\n    - { name: a, type: u16 }"),
+            "Struct S is 2 bytes long, which is not a whole number of words"
+        );
+        assert_eq!(
+            schema_error("structs:\n  S:\n    - { name: a, type: u32, count: 0 }"),
+            "Field S.a has a count of 0"
+        );
+        assert_eq!(
+            schema_error("structs:\n  S:\n    - { name: \"a b\", type: u32 }"),
+            "Struct S has an invalid field name 'a b'"
+        );
+        assert_eq!(
+            schema_error("labels:\n  TABLE: Missing"),
+            "Label TABLE uses undefined struct Missing"
+        );
+        assert!(schema_error("structs:\n  S:\n    - { name: a, type: u64 }").contains("u64"));
+    }
+}
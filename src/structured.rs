@@ -0,0 +1,229 @@
+//! JSON and YAML forms of the unpacked format.
+//!
+//! An unpacked archive is an object with a `words` array holding one element
+//! per 4-byte word, in address order:
+//!
+//! ```json
+//! {
+//!   "words": [
+//!     { "address": 0, "labels": ["DATA"], "kind": "pointer", "value": "1" },
+//!     { "address": 4, "kind": "string", "value": "Hello" },
+//!     { "address": 8, "destinations": [{ "name": "1" }], "kind": "raw", "value": "0x01000000" }
+//!   ],
+//!   "end_destinations": [{ "name": "END" }],
+//!   "end_labels": ["END"]
+//! }
+//! ```
+//!
+//! - `address`: offset of the word in the original archive. Informational
+//!   only, packing lays words out in array order.
+//! - `destinations`: pointer ids that `pointer` words refer to, each with an
+//!   optional byte `offset` into the word. Optional.
+//! - `labels`: labels placed at the word. Optional.
+//! - `kind` and `value`: a `pointer` holding a pointer id, a `string` holding
+//!   the text, or a `raw` word holding `0x` followed by 8 hex digits. A `null`
+//!   pointer has no `value`.
+//!
+//! `end_destinations` holds pointer ids that target the end of the data, and
+//! `end_labels` holds labels placed there. Both are optional.
+//!
+//! YAML uses the same schema. Since every word carries its `address`, and
+//! destinations and labels sit on the word they point at, the YAML output
+//! doubles as an offset map for cross-referencing a hex editor.
+
+use crate::model::{Entry, UnpackedArchive};
+use crate::unpacker::{format_word, parse_word};
+use anyhow::Context;
+use mila::BinArchive;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+struct Document {
+    words: Vec<Word>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    end_destinations: Vec<Destination>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    end_labels: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Word {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    address: Option<usize>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    destinations: Vec<Destination>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    labels: Vec<String>,
+
+    #[serde(flatten)]
+    value: WordValue,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Destination {
+    name: String,
+
+    #[serde(default, skip_serializing_if = "is_zero")]
+    offset: usize,
+}
+
+pub(crate) fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "lowercase")]
+enum WordValue {
+    Pointer(String),
+    Null,
+    String(String),
+    Raw(String),
+}
+
+fn to_document(archive: &UnpackedArchive) -> anyhow::Result<Document> {
+    let mut words: Vec<Word> = Vec::new();
+    let mut destinations: Vec<Destination> = Vec::new();
+    let mut labels: Vec<String> = Vec::new();
+    let mut addr = 0;
+    for entry in &archive.entries {
+        let value = match entry {
+            Entry::PointerDest(pointer_id, offset) => {
+                destinations.push(Destination {
+                    name: pointer_id.clone(),
+                    offset: *offset,
+                });
+                continue;
+            }
+            Entry::Label(label) => {
+                labels.push(label.clone());
+                continue;
+            }
+            Entry::PointerSource(pointer_id) => WordValue::Pointer(pointer_id.clone()),
+            Entry::NullPointer => WordValue::Null,
+            Entry::Text(text) => WordValue::String(text.clone()),
+            Entry::Word(data) | Entry::Typed(_, data) => WordValue::Raw(format_word(data)),
+            Entry::Field(name, _) => {
+                return Err(anyhow::anyhow!(
+                    "Field {} can only be represented in the text format.",
+                    name
+                ))
+            }
+        };
+        words.push(Word {
+            address: Some(addr),
+            destinations: std::mem::take(&mut destinations),
+            labels: std::mem::take(&mut labels),
+            value,
+        });
+        addr += 4;
+    }
+    Ok(Document {
+        words,
+        end_destinations: destinations,
+        end_labels: labels,
+    })
+}
+
+fn from_document(document: Document) -> anyhow::Result<UnpackedArchive> {
+    let mut archive = UnpackedArchive::new();
+    for (i, word) in document.words.into_iter().enumerate() {
+        archive.entries.extend(
+            word.destinations
+                .into_iter()
+                .map(|destination| Entry::PointerDest(destination.name, destination.offset)),
+        );
+        archive
+            .entries
+            .extend(word.labels.into_iter().map(Entry::Label));
+        archive.entries.push(match word.value {
+            WordValue::Pointer(pointer_id) => Entry::PointerSource(pointer_id),
+            WordValue::Null => Entry::NullPointer,
+            WordValue::String(text) => Entry::Text(text),
+            WordValue::Raw(hex) => Entry::Word(
+                parse_word(&hex).with_context(|| format!("Bad hex string in word {}", i))?,
+            ),
+        });
+    }
+    archive.entries.extend(
+        document
+            .end_destinations
+            .into_iter()
+            .map(|destination| Entry::PointerDest(destination.name, destination.offset)),
+    );
+    archive
+        .entries
+        .extend(document.end_labels.into_iter().map(Entry::Label));
+    Ok(archive)
+}
+
+pub fn unpack_json(archive: &BinArchive) -> anyhow::Result<String> {
+    serde_json::to_string_pretty(&to_document(&UnpackedArchive::from_archive(archive)?)?)
+        .context("Failed to serialize JSON.")
+}
+
+pub fn pack_json(text: &str) -> anyhow::Result<BinArchive> {
+    let document: Document = serde_json::from_str(text).context("Failed to parse JSON.")?;
+    from_document(document)?.to_archive()
+}
+
+pub fn unpack_yaml(archive: &BinArchive) -> anyhow::Result<String> {
+    serde_yaml::to_string(&to_document(&UnpackedArchive::from_archive(archive)?)?)
+        .context("Failed to serialize YAML.")
+}
+
+pub fn pack_yaml(text: &str) -> anyhow::Result<BinArchive> {
+    let document: Document = serde_yaml::from_str(text).context("Failed to parse YAML.")?;
+    from_document(document)?.to_archive()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unpacker::pack;
+
+    const TEXT: &str = "\
+LABEL: Table
+SRC: name
+SRC: END
+SRC: null
+DEST: name +2
+Hello
+0x01020304
+DEST: END";
+
+    #[test]
+    fn json_round_trips_byte_identical() {
+        let archive = pack(TEXT).unwrap();
+        let json = unpack_json(&archive).unwrap();
+        assert_eq!(
+            pack_json(&json).unwrap().serialize().unwrap(),
+            archive.serialize().unwrap()
+        );
+    }
+
+    #[test]
+    fn yaml_round_trips_byte_identical() {
+        let archive = pack(TEXT).unwrap();
+        let yaml = unpack_yaml(&archive).unwrap();
+        assert!(yaml.contains("address: 16"));
+        assert_eq!(
+            pack_yaml(&yaml).unwrap().serialize().unwrap(),
+            archive.serialize().unwrap()
+        );
+    }
+
+    #[test]
+    fn labels_at_the_end_of_data_round_trip() {
+        let archive = pack("SRC: END\n0x00000001\nDEST: END\nLABEL: END").unwrap();
+        let json = unpack_json(&archive).unwrap();
+        assert!(json.contains("\"end_labels\""));
+        assert_eq!(
+            pack_json(&json).unwrap().serialize().unwrap(),
+            archive.serialize().unwrap()
+        );
+    }
+}
@@ -1,103 +1,1054 @@
-use anyhow::Context;
-use mila::{BinArchive, BinArchiveWriter};
-use std::collections::HashMap;
+use crate::error::{PackError, PackErrorKind, PackErrors, PackWarning, PackWarningKind};
+use crate::header::{Compression, Header, FORMAT_VERSION, VERSION_PREFIX};
+use crate::merge::CONFLICT_SEPARATOR;
+use crate::model::{
+    misplaced_destinations, Comment, Entry, FieldValue, UnpackedArchive, NULL_POINTER,
+};
+use crate::quote::{quote, unquote};
+use crate::schema::{Endian, FieldType, ScalarType, Schema};
+use mila::BinArchive;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 
 pub fn unpack(archive: &BinArchive) -> anyhow::Result<String> {
-    let mut pointers: HashMap<usize, usize> = HashMap::new();
-    let mut pointer_destinations: HashMap<usize, usize> = HashMap::new();
-    for addr in (0..archive.size()).step_by(4) {
-        if let Some(ptr) = archive.read_pointer(addr)? {
-            let id = if let Some(id) = pointer_destinations.get(&ptr) {
-                *id
-            } else {
-                pointer_destinations.len()
-            };
-            pointer_destinations.insert(ptr, id);
-            pointers.insert(addr, id);
-        }
+    Ok(UnpackedArchive::from_archive(archive)?.to_text())
+}
+
+/// Options for `unpack_with_options`.
+///
+/// `schema` decodes the structs it describes into fields. `typed_words`
+/// displays any remaining raw words that look like numbers as typed words.
+/// `header` is written at the top of the text, and its byte order is used to
+/// read fields and typed words.
+#[derive(Default)]
+pub struct UnpackOptions<'a> {
+    pub schema: Option<&'a Schema>,
+    pub typed_words: bool,
+    pub header: Header,
+}
+
+pub fn unpack_with_options(
+    archive: &BinArchive,
+    options: &UnpackOptions,
+) -> anyhow::Result<String> {
+    let mut unpacked = UnpackedArchive::from_archive(archive)?;
+    unpacked.header = options.header.clone();
+    if let Some(schema) = options.schema {
+        unpacked.apply_schema(schema);
+    }
+    if options.typed_words {
+        unpacked.type_words();
     }
+    Ok(unpacked.to_text())
+}
 
-    let mut lines: Vec<String> = Vec::new();
-    for addr in (0..archive.size()).step_by(4) {
-        if let Some(id) = pointer_destinations.get(&addr) {
-            lines.push(format!("DEST: {}", id));
-        }
-        if let Some(labels) = archive.read_labels(addr)? {
-            for label in labels {
-                lines.push(format!("LABEL: {}", label));
-            }
+pub fn pack(text: &str) -> anyhow::Result<BinArchive> {
+    UnpackedArchive::from_text(text)?.to_archive()
+}
+
+/// The result of packing a text file, along with its header and any
+/// warnings.
+pub struct Packed {
+    pub archive: BinArchive,
+    pub header: Header,
+    pub warnings: Vec<PackWarning>,
+}
+
+impl From<BinArchive> for Packed {
+    fn from(archive: BinArchive) -> Self {
+        Packed {
+            archive,
+            header: Header::default(),
+            warnings: Vec::new(),
         }
-        if let Some(id) = pointers.get(&addr) {
-            lines.push(format!("SRC: {}", id));
-        } else if let Some(text) = archive.read_string(addr)? {
-            lines.push(text);
-        } else {
-            let data = archive.read_bytes(addr, 4)?;
-            lines.push(format!(
-                "0x{:02X}{:02X}{:02X}{:02X}",
-                data[0], data[1], data[2], data[3]
-            ));
+    }
+}
+
+/// Options for `pack_with_options`.
+///
+/// `max_errors` limits how many errors are kept, and must be at least 1.
+/// `endian` is the byte order the file is expected to use; if unset, the
+/// file's `ENDIAN` directive is used, or little-endian without one.
+/// `keep_comments` keeps comments in the parsed archive, so that `to_text`
+/// writes them back out.
+pub struct PackOptions {
+    pub max_errors: usize,
+    pub endian: Option<Endian>,
+    pub keep_comments: bool,
+}
+
+impl Default for PackOptions {
+    fn default() -> Self {
+        PackOptions {
+            max_errors: 1,
+            endian: None,
+            keep_comments: false,
         }
     }
-    Ok(lines.join("\n"))
+}
+
+pub fn pack_collecting(text: &str, max_errors: usize) -> anyhow::Result<Packed> {
+    pack_with_options(
+        text,
+        &PackOptions {
+            max_errors,
+            ..PackOptions::default()
+        },
+    )
+}
+
+pub fn pack_with_options(text: &str, options: &PackOptions) -> anyhow::Result<Packed> {
+    let (unpacked, warnings) = UnpackedArchive::from_text_with_options(text, options)?;
+    Ok(Packed {
+        archive: unpacked.to_archive()?,
+        header: unpacked.header,
+        warnings,
+    })
+}
+
+/// Rewrites a text file in the layout `unpack` uses, keeping its comments.
+///
+/// Blank lines and indentation are dropped, and directives and numbers are
+/// written the canonical way. Comments in the header are moved below it.
+pub fn reformat(text: &str, options: &PackOptions) -> anyhow::Result<String> {
+    let options = PackOptions {
+        keep_comments: true,
+        ..*options
+    };
+    let (unpacked, _) = UnpackedArchive::from_text_with_options(text, &options)?;
+    Ok(unpacked.to_text())
+}
+
+pub(crate) fn format_word(data: &[u8]) -> String {
+    let hex: String = data.iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!("0x{}", hex)
 }
 
 // Modified from: https://stackoverflow.com/questions/52987181/how-can-i-convert-a-hex-string-to-a-u8-slice
-fn decode_hex(s: &str) -> anyhow::Result<Vec<u8>> {
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
     if s.len() % 2 != 0 {
-        Err(anyhow::anyhow!("Hex string has odd length"))
+        None
     } else {
         (0..s.len())
             .step_by(2)
-            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|e| e.into()))
+            .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
             .collect()
     }
 }
 
-pub fn pack(text: &str) -> anyhow::Result<BinArchive> {
-    let lines: Vec<String> = text.split("\n").map(|l| l.trim().to_owned()).collect();
-    let size = lines
-        .iter()
-        .filter(|l| !l.starts_with("LABEL:") && !l.starts_with("DEST:") && !l.is_empty())
-        .count();
-
-    let mut pointers: HashMap<String, usize> = HashMap::new();
-    let mut pointer_sources: Vec<(usize, String)> = Vec::new();
-    let mut archive = BinArchive::new();
-    archive.allocate_at_end(size * 4);
-    let mut writer = BinArchiveWriter::new(&mut archive, 0);
-    for i in 0..lines.len() {
-        let line = &lines[i];
-        if line.starts_with("DEST:") {
-            let pointer_id = (&line[5..]).trim().to_owned();
-            pointers.insert(pointer_id, writer.tell());
-        } else if line.starts_with("SRC:") {
-            let pointer_id = (&line[4..]).trim().to_owned();
-            pointer_sources.push((writer.tell(), pointer_id));
-            writer.write_u32(0)?;
-        } else if line.starts_with("LABEL:") {
-            writer.write_label((&line[6..]).trim())?;
-        } else if line.starts_with("0x") {
-            let bytes = decode_hex(&line[2..])
-                .with_context(|| format!("Bad hex string at line {}", i + 1))?;
-            if bytes.len() != 4 {
-                return Err(anyhow::anyhow!(
-                    "Hex string has incorrect length at line {}",
-                    i + 1
-                ));
+pub(crate) fn parse_word(s: &str) -> Result<[u8; 4], PackErrorKind> {
+    let bytes = decode_hex(s.strip_prefix("0x").unwrap_or(s)).ok_or(PackErrorKind::BadHex)?;
+    if bytes.len() != 4 {
+        return Err(PackErrorKind::WrongHexLength);
+    }
+    Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// Directives that may only appear in the header, by prefix and name.
+const HEADER_DIRECTIVES: [(&str, &str); 5] = [
+    ("#!", "version"),
+    ("COMPRESSION:", "COMPRESSION"),
+    ("SOURCE:", "SOURCE"),
+    ("SHA1:", "SHA1"),
+    ("ENDIAN:", "ENDIAN"),
+];
+
+fn read_header_directive(
+    header: &mut Header,
+    name: &str,
+    line: &str,
+    argument: &str,
+    requested_endian: Option<Endian>,
+) -> Result<(), PackErrorKind> {
+    let malformed = || PackErrorKind::MalformedDirective(name.to_owned());
+    match name {
+        "version" => {
+            let version: u32 = line
+                .strip_prefix(VERSION_PREFIX)
+                .and_then(|version| version.parse().ok())
+                .filter(|version| *version > 0)
+                .ok_or_else(malformed)?;
+            if version > FORMAT_VERSION {
+                return Err(PackErrorKind::UnsupportedVersion(version));
             }
-            writer.write_bytes(&bytes)?;
-        } else {
-            writer.write_string(Some(&line))?;
         }
+        "COMPRESSION" => {
+            header.compression = Some(Compression::from_name(argument).ok_or_else(malformed)?);
+        }
+        "SOURCE" => {
+            if argument.is_empty() {
+                return Err(malformed());
+            }
+            header.source = Some(argument.to_owned());
+        }
+        "SHA1" => {
+            if argument.len() != 40 || !argument.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(malformed());
+            }
+            header.sha1 = Some(argument.to_ascii_lowercase());
+        }
+        "ENDIAN" => {
+            let declared = Endian::from_name(argument).ok_or_else(malformed)?;
+            match requested_endian {
+                Some(requested) if requested != declared => {
+                    return Err(PackErrorKind::EndianMismatch(
+                        declared.name().to_owned(),
+                        requested.name().to_owned(),
+                    ))
+                }
+                _ => header.endian = declared,
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+// Splits a trailing `//` or `;` comment, which must follow whitespace, off
+// the end of a line. Comments cannot start inside a quoted string.
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    let mut previous = None;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if quoted {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                quoted = false;
+            }
+        } else if c == '"' {
+            quoted = true;
+        } else if matches!(previous, Some(p) if char::is_whitespace(p))
+            && (c == ';' || line[i..].starts_with("//"))
+        {
+            return (line[..i].trim_end(), Some(&line[i..]));
+        }
+        previous = Some(c);
+    }
+    (line, None)
+}
+
+fn is_comment(line: &str) -> bool {
+    line.starts_with("//") || line.starts_with(';')
+}
+
+// Whether a string written as a plain line would be read back unchanged.
+// Anything that `from_text_with_options` would read as something other than
+// a string has to be written with `STR:` instead.
+fn reads_as_text(text: &str) -> bool {
+    const PREFIXES: [&str; 9] = [
+        "STR:", "DEST:", "SRC:", "LABEL:", "0x", "//", ";", "<<<<<<<", ">>>>>>>",
+    ];
+    let (code, _) = split_comment(text);
+    !text.is_empty()
+        && text == text.trim()
+        && !text.chars().any(char::is_control)
+        && !PREFIXES.iter().any(|prefix| text.starts_with(prefix))
+        && !HEADER_DIRECTIVES
+            .iter()
+            .any(|(prefix, _)| text.starts_with(prefix))
+        && text != CONFLICT_SEPARATOR
+        && typed_directive(code).is_none()
+        && split_field(code).is_none()
+}
+
+// Whether a label or string field value written as is after its directive
+// would be read back unchanged. Values starting with a quote are read as
+// quoted strings.
+fn reads_as_argument(text: &str) -> bool {
+    !text.is_empty()
+        && text == text.trim()
+        && !text.chars().any(char::is_control)
+        && !text.starts_with('"')
+        && split_comment(&format!(" {}", text)).1.is_none()
+}
+
+/// Whether a label can be used as is as the name in `SRC` and `DEST`.
+pub(crate) fn reads_as_name(label: &str) -> bool {
+    reads_as_argument(label) && !label.contains(" +")
+}
+
+// Recognizes a typed word directive such as `U16:`, returning its type and
+// its prefix.
+fn typed_directive(line: &str) -> Option<(ScalarType, &str)> {
+    let (name, _) = line.split_once(':')?;
+    if name.chars().any(|c| c.is_ascii_lowercase()) {
+        return None;
     }
-    for (addr, pointer_id) in pointer_sources {
-        if let Some(dest) = pointers.get(&pointer_id) {
-            println!("{:X}, {:X}, {}", addr, dest, pointer_id);
-            archive.write_pointer(addr, Some(*dest))?;
+    let scalar = ScalarType::from_name(&name.to_ascii_lowercase())?;
+    Some((scalar, &line[..name.len() + 1]))
+}
+
+// Names the directive a line starts with if it is only read as one in files
+// with a version line. Files without one predate these directives, so such
+// lines are strings.
+fn versioned_directive(line: &str) -> Option<&str> {
+    if line.starts_with("STR:") {
+        Some("STR")
+    } else if let Some((_, prefix)) = typed_directive(line) {
+        Some(prefix.trim_end_matches(':'))
+    } else if split_field(line).is_some() {
+        Some("field")
+    } else {
+        None
+    }
+}
+
+// Splits a `.name: type value` field line into its parts. Lines that do
+// not have this shape are plain strings.
+fn split_field(line: &str) -> Option<(&str, &str, &str)> {
+    let (name, rest) = line.strip_prefix('.')?.split_once(": ")?;
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return None;
+    }
+    let (type_name, value) = rest.split_once(' ').unwrap_or((rest, ""));
+    if type_name.is_empty() || !type_name.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    Some((name, type_name, value.trim()))
+}
+
+struct Line<'a> {
+    number: usize,
+    text: &'a str,
+    start: usize,
+    end: usize,
+}
+
+impl<'a> Line<'a> {
+    fn error(&self, span: Range<usize>, kind: PackErrorKind) -> PackError {
+        PackError {
+            line: self.number,
+            text: self.text.to_owned(),
+            span,
+            kind,
+        }
+    }
+
+    // Reads a quoted string that must make up all of `text`.
+    fn quoted(
+        &self,
+        text: &str,
+        directive: &str,
+        errors: &mut PackErrors,
+        max_errors: usize,
+    ) -> Option<String> {
+        let span = self.span_of(text);
+        let (range, kind) = if !text.starts_with('"') {
+            (
+                0..text.len(),
+                PackErrorKind::MalformedDirective(directive.to_owned()),
+            )
         } else {
-            return Err(anyhow::anyhow!("Unresolved pointer {}", pointer_id));
+            match unquote(text) {
+                Ok((unquoted, length)) if length == text.len() => return Some(unquoted),
+                Ok((_, length)) => {
+                    let rest = &text[length..];
+                    let start = text.len() - rest.trim_start().len();
+                    (
+                        start..text.len(),
+                        PackErrorKind::MalformedDirective(directive.to_owned()),
+                    )
+                }
+                Err(error) => error,
+            }
+        };
+        let span = span.start + range.start..span.start + range.end;
+        errors.push(self.error(span, kind), max_errors);
+        None
+    }
+
+    // The span of a slice of this line's text.
+    fn span_of(&self, part: &str) -> Range<usize> {
+        let start = part.as_ptr() as usize - self.text.as_ptr() as usize;
+        start..start + part.len()
+    }
+
+    // Parses numbers of the given type, reporting every invalid one.
+    fn numbers(
+        &self,
+        scalar: ScalarType,
+        type_name: &str,
+        numbers: impl Iterator<Item = &'a str>,
+        endian: Endian,
+        errors: &mut PackErrors,
+        max_errors: usize,
+    ) -> Option<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let mut valid = true;
+        for number in numbers {
+            match scalar.parse(number, endian) {
+                Some(parsed) => bytes.extend(parsed),
+                None => {
+                    let kind = PackErrorKind::BadValue(type_name.to_owned());
+                    errors.push(self.error(self.span_of(number), kind), max_errors);
+                    valid = false;
+                }
+            }
+        }
+        if valid {
+            Some(bytes)
+        } else {
+            None
+        }
+    }
+
+    // Splits a directive into its trimmed argument and the argument's span.
+    fn argument(&self, prefix: &str) -> (&'a str, Range<usize>) {
+        let rest = &self.text[self.start + prefix.len()..self.end];
+        let start = self.start + prefix.len() + rest.len() - rest.trim_start().len();
+        let argument = rest.trim();
+        (argument, start..start + argument.len())
+    }
+}
+
+fn entry_text(entry: &Entry, endian: Endian) -> String {
+    match entry {
+        Entry::Word(data) => format_word(data),
+        Entry::Text(text) if reads_as_text(text) => text.clone(),
+        Entry::Text(text) => format!("STR: {}", quote(text)),
+        Entry::PointerSource(pointer_id) => format!("SRC: {}", pointer_id),
+        Entry::NullPointer => format!("SRC: {}", NULL_POINTER),
+        Entry::PointerDest(pointer_id, 0) => format!("DEST: {}", pointer_id),
+        Entry::PointerDest(pointer_id, offset) => {
+            format!("DEST: {} +{}", pointer_id, offset)
+        }
+        Entry::Label(label) if reads_as_argument(label) => format!("LABEL: {}", label),
+        Entry::Label(label) => format!("LABEL: {}", quote(label)),
+        Entry::Typed(scalar, data) => {
+            let values: Vec<String> = data
+                .chunks(scalar.size())
+                .map(|value| scalar.format(value, endian))
+                .collect();
+            format!(
+                "{}: {}",
+                scalar.name().to_ascii_uppercase(),
+                values.join(", ")
+            )
+        }
+        Entry::Field(name, FieldValue::Numbers(scalar, bytes)) => {
+            let values: Vec<String> = bytes
+                .chunks(scalar.size())
+                .map(|value| scalar.format(value, endian))
+                .collect();
+            format!(".{}: {} {}", name, scalar.name(), values.join(" "))
+        }
+        Entry::Field(name, FieldValue::String(text)) if reads_as_argument(text) => {
+            format!(".{}: string {}", name, text)
+        }
+        Entry::Field(name, FieldValue::String(text)) => {
+            format!(".{}: string {}", name, quote(text))
+        }
+        Entry::Field(name, FieldValue::Pointer(pointer_id)) => {
+            format!(".{}: pointer {}", name, pointer_id)
+        }
+    }
+}
+
+impl UnpackedArchive {
+    pub fn to_text(&self) -> String {
+        let mut lines = self.header.lines();
+        lines.push(self.body_text());
+        lines.join("\n")
+    }
+
+    // The entries alone, without a header.
+    pub(crate) fn body_text(&self) -> String {
+        let mut comments = self.comments.iter().peekable();
+        let mut lines: Vec<String> = Vec::new();
+        for (index, entry) in self.entries.iter().enumerate() {
+            while let Some(comment) = comments.next_if(|comment| comment.index <= index) {
+                lines.push(comment.text.clone());
+            }
+            let mut line = entry_text(entry, self.header.endian);
+            if let Some(comment) =
+                comments.next_if(|comment| comment.trailing && comment.index == index + 1)
+            {
+                line.push(' ');
+                line.push_str(&comment.text);
+            }
+            lines.push(line);
+        }
+        lines.extend(comments.map(|comment| comment.text.clone()));
+        lines.join("\n")
+    }
+
+    pub fn from_text(text: &str) -> Result<Self, PackError> {
+        Self::from_text_collecting(text, 1)
+            .map(|(archive, _)| archive)
+            .map_err(|mut errors| errors.errors.remove(0))
+    }
+
+    /// Parses the text format without stopping at the first error.
+    ///
+    /// Keeps at most `max_errors` errors, which must be at least 1.
+    pub fn from_text_collecting(
+        text: &str,
+        max_errors: usize,
+    ) -> Result<(Self, Vec<PackWarning>), PackErrors> {
+        let options = PackOptions {
+            max_errors,
+            ..PackOptions::default()
+        };
+        Self::from_text_with_options(text, &options)
+    }
+
+    /// Parses the text format.
+    ///
+    /// `//` and `;` comments, `STR:`, typed words and fields are only
+    /// recognized in files with a version line, which may itself follow
+    /// comments. In files without one, which predate them, such lines are
+    /// strings.
+    pub fn from_text_with_options(
+        text: &str,
+        options: &PackOptions,
+    ) -> Result<(Self, Vec<PackWarning>), PackErrors> {
+        let max_errors = options.max_errors;
+        let mut header = Header {
+            endian: options.endian.unwrap_or_default(),
+            ..Header::default()
+        };
+        let mut seen_directives: HashSet<&str> = HashSet::new();
+        let mut undeclared_endian: Option<PackError> = None;
+        let mut errors = PackErrors::new();
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut comments: Vec<Comment> = Vec::new();
+        let mut warnings: Vec<PackWarning> = Vec::new();
+        let mut pointers: HashMap<&str, (Line, Range<usize>)> = HashMap::new();
+        let mut pointer_sources: Vec<(Line, Range<usize>)> = Vec::new();
+        // Files written before comments and the newer directives existed
+        // have no version line, and may have strings that look like them.
+        let first_line = text
+            .split('\n')
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !is_comment(line));
+        let versioned = matches!(first_line, Some(line) if line.starts_with("#!"));
+        for (i, raw) in text.split('\n').enumerate() {
+            let raw = raw.trim_end_matches('\r');
+            let trimmed = raw.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+            if is_comment(trimmed) {
+                if !versioned {
+                    let start = raw.len() - trimmed.len();
+                    warnings.push(PackWarning {
+                        line: i + 1,
+                        text: raw.to_owned(),
+                        span: start..start + trimmed.len(),
+                        kind: PackWarningKind::CommentWithoutVersion,
+                    });
+                } else {
+                    if options.keep_comments {
+                        comments.push(Comment {
+                            index: entries.len(),
+                            text: trimmed.to_owned(),
+                            trailing: false,
+                        });
+                    }
+                    continue;
+                }
+            }
+            // Strings are taken literally, so only directives can have
+            // trailing comments. `code` is the line without one.
+            let (code, mut comment) = if versioned {
+                split_comment(trimmed)
+            } else {
+                (trimmed, None)
+            };
+            let start = raw.len() - raw.trim_start().len();
+            let line = Line {
+                number: i + 1,
+                text: raw,
+                start,
+                end: start + code.len(),
+            };
+            let header_directive = HEADER_DIRECTIVES
+                .iter()
+                .find(|(prefix, _)| code.starts_with(prefix));
+            let entry = if let Some((prefix, name)) = header_directive {
+                let (argument, span) = line.argument(prefix);
+                // The header says how the rest of the file is read, so it
+                // cannot change once data has started.
+                if !entries.is_empty() {
+                    let kind = PackErrorKind::MisplacedDirective((*name).to_owned());
+                    errors.push(line.error(span, kind), max_errors);
+                } else if !seen_directives.insert(*name) {
+                    let kind = PackErrorKind::DuplicateDirective((*name).to_owned());
+                    errors.push(line.error(span, kind), max_errors);
+                } else if let Err(kind) =
+                    read_header_directive(&mut header, name, code, argument, options.endian)
+                {
+                    // Nothing else in a file from a newer version can be
+                    // trusted to mean the same thing.
+                    let unsupported = matches!(kind, PackErrorKind::UnsupportedVersion(_));
+                    errors.push(line.error(span, kind), max_errors);
+                    if unsupported {
+                        return Err(errors);
+                    }
+                } else if *name == "version" && !seen_directives.contains("ENDIAN") {
+                    // Files with a header always had their byte order
+                    // written down, so a missing ENDIAN means little.
+                    undeclared_endian = options
+                        .endian
+                        .filter(|requested| *requested != Endian::Little)
+                        .map(|requested| {
+                            let kind = PackErrorKind::EndianMismatch(
+                                Endian::Little.name().to_owned(),
+                                requested.name().to_owned(),
+                            );
+                            line.error(span, kind)
+                        });
+                }
+                if *name == "ENDIAN" {
+                    undeclared_endian = None;
+                }
+                // There is no entry to attach the comment to, so it is
+                // kept on a line of its own.
+                if let (Some(text), true) = (comment, options.keep_comments) {
+                    comments.push(Comment {
+                        index: entries.len(),
+                        text: text.to_owned(),
+                        trailing: false,
+                    });
+                }
+                continue;
+            } else if versioned && code.starts_with("STR:") {
+                let (argument, _) = line.argument("STR:");
+                match line.quoted(argument, "STR", &mut errors, max_errors) {
+                    Some(text) => Entry::Text(text),
+                    None => continue,
+                }
+            } else if trimmed.starts_with("DEST:") {
+                let (argument, span) = line.argument("DEST:");
+                let (pointer_id, offset) = match argument.rsplit_once(" +") {
+                    Some((pointer_id, offset)) => (pointer_id.trim_end(), offset.parse().ok()),
+                    None => (argument, Some(0)),
+                };
+                let offset = match offset {
+                    Some(offset) => offset,
+                    None => {
+                        let kind = PackErrorKind::MalformedDirective("DEST".into());
+                        errors.push(line.error(span, kind), max_errors);
+                        continue;
+                    }
+                };
+                let span = span.start..span.start + pointer_id.len();
+                if pointer_id.is_empty() {
+                    let kind = PackErrorKind::MalformedDirective("DEST".into());
+                    errors.push(line.error(span, kind), max_errors);
+                    continue;
+                }
+                if pointer_id == NULL_POINTER {
+                    let kind = PackErrorKind::ReservedName(pointer_id.into());
+                    errors.push(line.error(span, kind), max_errors);
+                    continue;
+                }
+                if pointers.contains_key(pointer_id) {
+                    let kind = PackErrorKind::DuplicateDest(pointer_id.into());
+                    errors.push(line.error(span, kind), max_errors);
+                    continue;
+                }
+                pointers.insert(pointer_id, (line, span));
+                Entry::PointerDest(pointer_id.to_owned(), offset)
+            } else if trimmed.starts_with("SRC:") {
+                let (pointer_id, span) = line.argument("SRC:");
+                if pointer_id.is_empty() {
+                    let kind = PackErrorKind::MalformedDirective("SRC".into());
+                    errors.push(line.error(span, kind), max_errors);
+                    continue;
+                }
+                if pointer_id == NULL_POINTER {
+                    Entry::NullPointer
+                } else {
+                    let entry = Entry::PointerSource(pointer_id.to_owned());
+                    pointer_sources.push((line, span));
+                    entry
+                }
+            } else if trimmed.starts_with("LABEL:") {
+                let (label, span) = line.argument("LABEL:");
+                if label.is_empty() {
+                    let kind = PackErrorKind::MalformedDirective("LABEL".into());
+                    errors.push(line.error(span, kind), max_errors);
+                    continue;
+                }
+                if label.starts_with('"') {
+                    match line.quoted(label, "LABEL", &mut errors, max_errors) {
+                        Some(label) => Entry::Label(label),
+                        None => continue,
+                    }
+                } else {
+                    Entry::Label(label.to_owned())
+                }
+            } else if code.starts_with("0x") {
+                match parse_word(code) {
+                    Ok(data) => Entry::Word(data),
+                    Err(kind) => {
+                        let span = line.start..line.end;
+                        errors.push(line.error(span, kind), max_errors);
+                        continue;
+                    }
+                }
+            } else if let Some((scalar, prefix)) = typed_directive(code).filter(|_| versioned) {
+                let (argument, span) = line.argument(prefix);
+                let directive = prefix.trim_end_matches(':');
+                let numbers = argument.split(',').map(str::trim);
+                let bytes = match line.numbers(
+                    scalar,
+                    directive,
+                    numbers,
+                    header.endian,
+                    &mut errors,
+                    max_errors,
+                ) {
+                    Some(bytes) => bytes,
+                    None => continue,
+                };
+                if bytes.len() != 4 {
+                    let kind = PackErrorKind::WrongWordSize(directive.to_owned());
+                    errors.push(line.error(span, kind), max_errors);
+                    continue;
+                }
+                Entry::Typed(scalar, [bytes[0], bytes[1], bytes[2], bytes[3]])
+            } else if let Some((name, type_name, value)) = split_field(code).filter(|_| versioned) {
+                let kind = match FieldType::from_name(type_name) {
+                    Some(kind) => kind,
+                    None => {
+                        let kind = PackErrorKind::UnknownType(type_name.to_owned());
+                        errors.push(line.error(line.span_of(type_name), kind), max_errors);
+                        continue;
+                    }
+                };
+                // Unquoted strings are taken literally, comments and all.
+                let value = match split_field(trimmed) {
+                    Some((_, _, literal))
+                        if kind == FieldType::String && !literal.starts_with('"') =>
+                    {
+                        comment = None;
+                        literal
+                    }
+                    _ => value,
+                };
+                if value.is_empty() {
+                    let kind = PackErrorKind::MalformedDirective("field".into());
+                    errors.push(line.error(line.span_of(trimmed), kind), max_errors);
+                    continue;
+                }
+                let value = match kind {
+                    FieldType::Scalar(scalar) => {
+                        let numbers = value.split_whitespace();
+                        match line.numbers(
+                            scalar,
+                            type_name,
+                            numbers,
+                            header.endian,
+                            &mut errors,
+                            max_errors,
+                        ) {
+                            Some(bytes) => FieldValue::Numbers(scalar, bytes),
+                            None => continue,
+                        }
+                    }
+                    FieldType::String if value.starts_with('"') => {
+                        match line.quoted(value, "field", &mut errors, max_errors) {
+                            Some(text) => FieldValue::String(text),
+                            None => continue,
+                        }
+                    }
+                    FieldType::String => FieldValue::String(value.to_owned()),
+                    FieldType::Pointer => {
+                        if value != NULL_POINTER {
+                            let span = line.span_of(value);
+                            pointer_sources.push((line, span));
+                        }
+                        FieldValue::Pointer(value.to_owned())
+                    }
+                };
+                Entry::Field(name.to_owned(), value)
+            } else if trimmed.starts_with("<<<<<<<")
+                || trimmed == CONFLICT_SEPARATOR
+                || trimmed.starts_with(">>>>>>>")
+            {
+                let span = line.start..line.start + trimmed.len();
+                errors.push(line.error(span, PackErrorKind::ConflictMarker), max_errors);
+                continue;
+            } else {
+                if let (false, Some(directive)) = (versioned, versioned_directive(trimmed)) {
+                    warnings.push(PackWarning {
+                        line: i + 1,
+                        text: raw.to_owned(),
+                        span: line.start..line.start + trimmed.len(),
+                        kind: PackWarningKind::DirectiveWithoutVersion(directive.to_owned()),
+                    });
+                }
+                comment = None;
+                Entry::Text(trimmed.to_owned())
+            };
+            entries.push(entry);
+            if let (Some(text), true) = (comment, options.keep_comments) {
+                comments.push(Comment {
+                    index: entries.len(),
+                    text: text.to_owned(),
+                    trailing: true,
+                });
+            }
+        }
+        if let Some(error) = undeclared_endian {
+            errors.push(error, max_errors);
+        }
+        for index in misplaced_destinations(&entries) {
+            if let Entry::PointerDest(pointer_id, offset) = &entries[index] {
+                let (line, span) = &pointers[pointer_id.as_str()];
+                let kind = PackErrorKind::DestOutOfRange(pointer_id.clone(), *offset);
+                errors.push(line.error(span.clone(), kind), max_errors);
+            }
+        }
+        let mut referenced: HashSet<&str> = HashSet::new();
+        for (line, span) in pointer_sources {
+            let pointer_id = &line.text[span.clone()];
+            if pointers.contains_key(pointer_id) {
+                referenced.insert(pointer_id);
+            } else {
+                let kind = PackErrorKind::UnresolvedPointer(pointer_id.to_owned());
+                errors.push(line.error(span, kind), max_errors);
+            }
+        }
+        if errors.total > 0 {
+            errors.errors.sort_by_key(|error| error.line);
+            return Err(errors);
+        }
+        warnings.extend(
+            pointers
+                .into_iter()
+                .filter(|(pointer_id, _)| !referenced.contains(pointer_id))
+                .map(|(pointer_id, (line, span))| PackWarning {
+                    line: line.number,
+                    text: line.text.to_owned(),
+                    span,
+                    kind: PackWarningKind::UnusedDest(pointer_id.to_owned()),
+                }),
+        );
+        warnings.sort_by_key(|warning| warning.line);
+        let unpacked = UnpackedArchive {
+            entries,
+            header,
+            comments,
+        };
+        Ok((unpacked, warnings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::info::archive_info;
+
+    fn error_kind(text: &str) -> PackErrorKind {
+        UnpackedArchive::from_text(text).unwrap_err().kind
+    }
+
+    #[test]
+    fn round_trips_every_directive() {
+        let text = "\
+0x01020304
+LABEL: Table
+SRC: ptr_0x10
+SRC: null
+STR: \"two\\nlines\"
+DEST: ptr_0x10
+plain
+LABEL: END";
+        let versioned = format!("#! asset-pack v1\n{}", text);
+        let unpacked = unpack(&pack(&versioned).unwrap()).unwrap();
+        assert_eq!(
+            unpacked
+                .lines()
+                .skip(Header::default().lines().len())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            text
+        );
+    }
+
+    #[test]
+    fn pointers_to_a_labeled_start_are_not_null() {
+        let text = "LABEL: a b\nDEST: q\n0x00000000\nSRC: q";
+        let unpacked = unpack(&pack(text).unwrap()).unwrap();
+        let lines: Vec<&str> = unpacked
+            .lines()
+            .skip(Header::default().lines().len())
+            .collect();
+        assert_eq!(
+            lines,
+            vec!["DEST: a b", "LABEL: a b", "0x00000000", "SRC: a b"]
+        );
+        assert_eq!(archive_info(&pack(text).unwrap()).unwrap().null_pointers, 0);
+
+        let unlabeled = pack("0x00000000\nSRC: null").unwrap();
+        assert!(unpack(&unlabeled).unwrap().ends_with("SRC: null"));
+        assert_eq!(archive_info(&unlabeled).unwrap().null_pointers, 1);
+    }
+
+    #[test]
+    fn typed_words_pack_little_endian() {
+        let text = "#! asset-pack v1\nU32: 4660\nI16: -1, 2";
+        let unpacked = UnpackedArchive::from_text(text).unwrap();
+        let archive = unpacked.to_archive().unwrap();
+        assert_eq!(
+            archive.read_bytes(0, 8).unwrap()[..],
+            [0x34, 0x12, 0, 0, 0xFF, 0xFF, 2, 0]
+        );
+    }
+
+    #[test]
+    fn reports_malformed_lines() {
+        assert_eq!(error_kind("0x0102"), PackErrorKind::WrongHexLength);
+        assert_eq!(error_kind("0xZZZZZZZZ"), PackErrorKind::BadHex);
+        assert_eq!(
+            error_kind("SRC: missing"),
+            PackErrorKind::UnresolvedPointer("missing".into())
+        );
+    }
+
+    #[test]
+    fn rejects_destinations_outside_their_word() {
+        assert_eq!(
+            error_kind("SRC: a\nDEST: a +4\n0x00000000"),
+            PackErrorKind::DestOutOfRange("a".into(), 4)
+        );
+    }
+
+    #[test]
+    fn rejects_header_directives_after_data() {
+        assert_eq!(
+            error_kind("0x00000000\nCOMPRESSION: none"),
+            PackErrorKind::MisplacedDirective("COMPRESSION".into())
+        );
+    }
+
+    #[test]
+    fn versioned_files_without_endian_are_little() {
+        let options = PackOptions {
+            endian: Some(Endian::Big),
+            ..PackOptions::default()
+        };
+        let errors =
+            UnpackedArchive::from_text_with_options("#! asset-pack v1\nU32: 4660", &options)
+                .unwrap_err();
+        assert_eq!(
+            errors.errors[0].kind,
+            PackErrorKind::EndianMismatch("little".into(), "big".into())
+        );
+        assert!(UnpackedArchive::from_text_with_options("U32: 4660", &options).is_ok());
+        let declared = "#! asset-pack v1\nENDIAN: big\nU32: 4660";
+        assert!(UnpackedArchive::from_text_with_options(declared, &options).is_ok());
+    }
+
+    #[test]
+    fn reformat_keeps_comments() {
+        let text = "#! asset-pack v1\n; table\n0x00000001 // first\n0x00000002";
+        let options = PackOptions::default();
+        let reformatted = reformat(text, &options).unwrap();
+        assert_eq!(
+            reformatted
+                .lines()
+                .skip(Header::default().lines().len())
+                .collect::<Vec<_>>(),
+            vec!["; table", "0x00000001 // first", "0x00000002"]
+        );
+        assert_eq!(pack(text).unwrap().size(), 8);
+    }
+
+    #[test]
+    fn comments_need_a_version_line() {
+        let (unpacked, warnings) =
+            UnpackedArchive::from_text_collecting("// not a comment\n0x00000001", 1).unwrap();
+        assert_eq!(unpacked.entries[0], Entry::Text("// not a comment".into()));
+        assert_eq!(warnings[0].kind, PackWarningKind::CommentWithoutVersion);
+
+        let (unpacked, warnings) =
+            UnpackedArchive::from_text_collecting("// notes\n#! asset-pack v1\n0x00000000", 1)
+                .unwrap();
+        assert_eq!(unpacked.entries, vec![Entry::Word([0; 4])]);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn newer_directives_need_a_version_line() {
+        let text = "U32: 1\nSTR: \"a\"\n.x: u8 1";
+        let (unpacked, warnings) = UnpackedArchive::from_text_collecting(text, 1).unwrap();
+        assert_eq!(
+            unpacked.entries,
+            text.lines()
+                .map(|line| Entry::Text(line.to_owned()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            warnings
+                .into_iter()
+                .map(|warning| warning.kind)
+                .collect::<Vec<_>>(),
+            vec![
+                PackWarningKind::DirectiveWithoutVersion("U32".into()),
+                PackWarningKind::DirectiveWithoutVersion("STR".into()),
+                PackWarningKind::DirectiveWithoutVersion("field".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn labels_and_values_with_comment_markers_round_trip() {
+        let unpacked = UnpackedArchive {
+            entries: vec![
+                Entry::Label("a ;b".into()),
+                Entry::Field("name".into(), FieldValue::String("c // d".into())),
+                Entry::Label("\"quoted\"".into()),
+                Entry::Text("e ;f".into()),
+            ],
+            ..UnpackedArchive::default()
+        };
+        let parsed = UnpackedArchive::from_text(&unpacked.to_text()).unwrap();
+        assert_eq!(parsed.entries, unpacked.entries);
+    }
+
+    #[test]
+    fn strings_that_look_like_directives_round_trip() {
+        let strings = [
+            "",
+            " padded ",
+            "LABEL: foo",
+            "SRC: 3",
+            "DEST: a",
+            "0x12345678",
+            "STR: \"x\"",
+            "U32: 1",
+            ".x: u8 = 1",
+            "#! asset-pack v1",
+            "// note",
+            "; note",
+            "a // b",
+            "<<<<<<< ours",
+            "line\nbreak",
+        ];
+        let original = UnpackedArchive {
+            entries: strings
+                .iter()
+                .map(|text| Entry::Text((*text).to_owned()))
+                .collect(),
+            ..UnpackedArchive::default()
+        }
+        .to_archive()
+        .unwrap();
+        let repacked = pack(&unpack(&original).unwrap()).unwrap();
+        assert_eq!(repacked.serialize().unwrap(), original.serialize().unwrap());
+        for (index, text) in strings.iter().enumerate() {
+            assert_eq!(
+                repacked.read_string(index * 4).unwrap().as_deref(),
+                Some(*text)
+            );
         }
     }
-    Ok(archive)
 }
@@ -0,0 +1,165 @@
+use crate::model::{Entry, UnpackedArchive};
+use crate::unpacker::format_word;
+use mila::BinArchive;
+use std::fmt;
+
+// Serialized archives start with a fixed-size header followed by the data.
+const HEADER_SIZE: usize = 0x20;
+const CONTEXT: usize = 16;
+
+/// The first difference between an archive and its repacked form.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub offset: usize,
+    pub window_start: usize,
+    pub expected: Vec<u8>,
+    pub actual: Vec<u8>,
+    pub location: String,
+}
+
+fn hex_line(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "First difference at offset 0x{:X} in {}",
+            self.offset, self.location
+        )?;
+        writeln!(
+            f,
+            "  original 0x{:08X}: {}",
+            self.window_start,
+            hex_line(&self.expected)
+        )?;
+        write!(
+            f,
+            "  repacked 0x{:08X}: {}",
+            self.window_start,
+            hex_line(&self.actual)
+        )
+    }
+}
+
+fn describe_entry(entry: &Entry) -> String {
+    match entry {
+        Entry::Word(data) | Entry::Typed(_, data) => format!("raw word {}", format_word(data)),
+        Entry::Text(text) => format!("string {:?}", text),
+        Entry::PointerSource(pointer_id) => format!("pointer to {}", pointer_id),
+        Entry::NullPointer => "null pointer".to_owned(),
+        Entry::PointerDest(pointer_id, _) => format!("destination {}", pointer_id),
+        Entry::Label(label) => format!("label {}", label),
+        Entry::Field(name, _) => format!("field {}", name),
+    }
+}
+
+fn locate(unpacked: &UnpackedArchive, offset: usize) -> String {
+    if offset < HEADER_SIZE {
+        return "the header".to_owned();
+    }
+    let target = offset - HEADER_SIZE;
+    let mut addr = 0;
+    let mut label: Option<(&str, usize)> = None;
+    for entry in &unpacked.entries {
+        match entry {
+            Entry::Label(name) => label = Some((name, addr)),
+            Entry::PointerDest(_, _) => {}
+            _ => {
+                if target < addr + entry.size() {
+                    let relative = label
+                        .map(|(name, start)| format!(" ({} + 0x{:X})", name, addr - start))
+                        .unwrap_or_default();
+                    return format!(
+                        "{} at data address 0x{:X}{}",
+                        describe_entry(entry),
+                        addr,
+                        relative
+                    );
+                }
+                addr += entry.size();
+            }
+        }
+    }
+    "the pointer, label and text tables".to_owned()
+}
+
+/// Unpacks an archive to text, packs it again and compares the serialized
+/// result with the original bytes.
+///
+/// Returns the first difference, or `None` if the round trip is exact.
+pub fn verify_roundtrip(bytes: &[u8]) -> anyhow::Result<Option<Mismatch>> {
+    let archive = BinArchive::from_bytes(bytes)?;
+    let unpacked = UnpackedArchive::from_archive(&archive)?;
+    let repacked = UnpackedArchive::from_text(&unpacked.to_text())?
+        .to_archive()?
+        .serialize()?;
+    Ok(compare(bytes, &repacked, &unpacked))
+}
+
+// Finds the first difference between the original and repacked bytes.
+// `unpacked` is the original archive, used to describe where it is.
+fn compare(bytes: &[u8], repacked: &[u8], unpacked: &UnpackedArchive) -> Option<Mismatch> {
+    let offset = match bytes.iter().zip(repacked).position(|(a, b)| a != b) {
+        Some(offset) => offset,
+        None if bytes.len() == repacked.len() => return None,
+        None => bytes.len().min(repacked.len()),
+    };
+    let window_start = offset.saturating_sub(CONTEXT);
+    let window = |data: &[u8]| {
+        data[window_start.min(data.len())..(offset + CONTEXT).min(data.len())].to_vec()
+    };
+    Some(Mismatch {
+        offset,
+        window_start,
+        expected: window(bytes),
+        actual: window(repacked),
+        location: locate(unpacked, offset),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unpacker::pack;
+
+    const TEXT: &str = "0x00000000\nLABEL: Table\n0x01020304\nHello";
+
+    #[test]
+    fn exact_round_trip_has_no_mismatch() {
+        let bytes = pack(TEXT).unwrap().serialize().unwrap();
+        assert_eq!(verify_roundtrip(&bytes).unwrap(), None);
+    }
+
+    #[test]
+    fn reports_the_first_difference() {
+        let archive = pack(TEXT).unwrap();
+        let bytes = archive.serialize().unwrap();
+        let mut repacked = bytes.clone();
+        repacked[HEADER_SIZE + 6] ^= 0xFF;
+        let unpacked = UnpackedArchive::from_archive(&archive).unwrap();
+
+        let mismatch = compare(&bytes, &repacked, &unpacked).unwrap();
+        assert_eq!(mismatch.offset, HEADER_SIZE + 6);
+        assert_eq!(mismatch.window_start, HEADER_SIZE + 6 - CONTEXT);
+        assert_eq!(
+            mismatch.expected,
+            bytes[HEADER_SIZE + 6 - CONTEXT..][..2 * CONTEXT]
+        );
+        assert_eq!(mismatch.actual[CONTEXT], bytes[HEADER_SIZE + 6] ^ 0xFF);
+        assert_eq!(
+            mismatch.location,
+            "raw word 0x01020304 at data address 0x4 (Table + 0x0)"
+        );
+        assert_eq!(
+            locate(&unpacked, HEADER_SIZE + 8),
+            "string \"Hello\" at data address 0x8 (Table + 0x4)"
+        );
+        assert_eq!(locate(&unpacked, 4), "the header");
+    }
+}